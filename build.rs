@@ -0,0 +1,233 @@
+//! Finds the branch in `DebugTuple::field` at build time.
+//!
+//! A tiny probe is compiled as a static library for the target, which bundles the `std` of the
+//! active toolchain, and `DebugTuple::field` is looked up in it and run through the same scan that
+//! `enable` uses. If the branch is not there, the build fails right away rather than `enable` at
+//! runtime; otherwise, where it was found is passed on to the crate as constants.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+// Only the original instructions are of interest here, not what they are patched to.
+#[allow(dead_code)]
+#[path = "src/scan/aarch64.rs"]
+mod aarch64;
+#[allow(dead_code)]
+#[path = "src/demangle.rs"]
+mod demangle;
+#[allow(dead_code)]
+#[path = "src/elf.rs"]
+mod elf;
+#[allow(dead_code)]
+#[path = "src/scan/riscv64.rs"]
+mod riscv64;
+#[path = "src/scan.rs"]
+mod scan;
+#[allow(dead_code)]
+#[path = "src/toolchains.rs"]
+mod toolchains;
+#[allow(dead_code)]
+#[path = "src/scan/x86.rs"]
+mod x86;
+
+/// The probe, which only needs to refer to the function for `std` to be bundled with it.
+const PROBE: &str = "pub fn probe() -> usize { core::fmt::DebugTuple::field as usize }\n";
+
+fn main() {
+	let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+	let verbose = Command::new(&rustc)
+		.arg("-vV")
+		.output()
+		.map(|out| String::from_utf8_lossy(&out.stdout).into_owned())
+		.unwrap_or_else(|e| format!("(could not run rustc -vV: {e})\n"));
+	let field = |key: &str| {
+		verbose
+			.lines()
+			.find_map(|l| l.strip_prefix(key)?.strip_prefix(": "))
+			.unwrap_or("unknown")
+	};
+	// The first line is the same as `rustc --version`.
+	let version = verbose.lines().next().unwrap_or_default();
+	println!("cargo:rustc-env=COMPACT_DEBUG_RUSTC_VERSION={version}");
+	println!("cargo:rerun-if-changed=build.rs");
+	println!("cargo:rerun-if-changed=src/demangle.rs");
+	println!("cargo:rerun-if-changed=src/elf.rs");
+	println!("cargo:rerun-if-changed=src/scan");
+	println!("cargo:rerun-if-changed=src/scan.rs");
+	println!("cargo:rerun-if-changed=src/toolchains.rs");
+
+	let (release, commit) = (field("release"), field("commit-hash"));
+	let target = env::var("TARGET").unwrap();
+	println!("cargo:rustc-env=COMPACT_DEBUG_RUSTC_RELEASE={release}");
+	println!("cargo:rustc-env=COMPACT_DEBUG_RUSTC_COMMIT={commit}");
+	println!("cargo:rustc-env=COMPACT_DEBUG_TARGET={target}");
+
+	let entry = toolchains::TOOLCHAINS
+		.iter()
+		.find(|t| t.commit == commit && t.target == target);
+	let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+	let sites = match probe(&rustc, &out_dir) {
+		Ok(sites) => sites,
+		Err(Probe::Skipped) => Vec::new(),
+		Err(Probe::Failed(msg)) => {
+			if entry.is_some() {
+				eprintln!(
+					"error: compact-debug does not work with this toolchain, though it should"
+				);
+			} else {
+				eprintln!("error: rustc {release} not yet supported by compact-debug");
+			}
+			eprintln!();
+			eprintln!("{}", msg.trim_end());
+			eprintln!();
+			eprintln!("rustc -vV:");
+			eprint!("{verbose}");
+			process::exit(1);
+		}
+	};
+
+	if let (Some(t), Some((offset, original))) = (entry, sites.first()) {
+		if (t.offset, t.original) != (*offset, &original[..]) {
+			println!(
+				"cargo:warning=DebugTuple::field has {original:02x?} at {offset:#x}, \
+				 but the known toolchains say {:02x?} at {:#x}",
+				t.original, t.offset
+			);
+		}
+	}
+
+	let mut offsets = String::new();
+	let mut original = String::new();
+	for (offset, bytes) in &sites {
+		write!(offsets, "{offset:#x}, ").unwrap();
+		write!(original, "&{bytes:?}, ").unwrap();
+	}
+	let out = format!(
+		"/// Where the build script found the branches in `DebugTuple::field`, as offsets into it.\n\
+		 const FIELD_OFFSETS: &[usize] = &[{offsets}];\n\
+		 /// What the build script found at each of `FIELD_OFFSETS`.\n\
+		 const FIELD_ORIGINAL: &[&[u8]] = &[{original}];\n"
+	);
+	fs::write(out_dir.join("sites.rs"), out).unwrap();
+}
+
+enum Probe {
+	/// There is nothing to check on this target.
+	Skipped,
+	Failed(String),
+}
+
+/// Compiles the probe and scans `DebugTuple::field` in it, returning the offset and original bytes
+/// of each branch.
+fn probe(rustc: &std::ffi::OsStr, out_dir: &Path) -> Result<Vec<(usize, Vec<u8>)>, Probe> {
+	let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+	if !matches!(&*arch, "x86_64" | "x86" | "aarch64" | "riscv64") {
+		return Err(Probe::Skipped);
+	}
+
+	let src = out_dir.join("probe.rs");
+	let lib = out_dir.join("libcompact_debug_probe.a");
+	fs::write(&src, PROBE).unwrap();
+	let out = Command::new(rustc)
+		.args(["--crate-type=staticlib", "--crate-name=compact_debug_probe"])
+		.args(["--edition=2021", "--cap-lints=allow", "--target"])
+		.arg(env::var("TARGET").unwrap())
+		.arg("-o")
+		.arg(&lib)
+		.arg(&src)
+		.output()
+		.map_err(|e| Probe::Failed(format!("could not run rustc on the probe: {e}")))?;
+	if !out.status.success() {
+		return Err(Probe::Failed(format!(
+			"could not compile the probe:\n{}",
+			String::from_utf8_lossy(&out.stderr)
+		)));
+	}
+	let archive = fs::read(&lib).unwrap();
+	// The archive holds all of `std`, which is a bit much to leave lying around.
+	let _ = fs::remove_file(&lib);
+
+	// Only ELF is understood here, so other targets are left to be checked at runtime.
+	let mut elf = false;
+	let mut code = None;
+	for member in members(&archive) {
+		if member.starts_with(b"\x7FELF") {
+			elf = true;
+			code = code.or_else(|| find_field(member));
+		}
+	}
+	let Some(code) = code else {
+		return if elf {
+			Err(Probe::Failed(
+				"DebugTuple::field is not in the probe".into(),
+			))
+		} else {
+			Err(Probe::Skipped)
+		};
+	};
+
+	let sites: Vec<_> = match &*arch {
+		"x86_64" | "x86" => x86::scan(code, arch == "x86_64")
+			.unwrap_or_default()
+			.into_iter()
+			.map(|f| (f.offset, f.original.to_le_bytes().to_vec()))
+			.collect(),
+		"aarch64" => aarch64::scan(code)
+			.into_iter()
+			.map(|f| (f.offset, f.original.to_le_bytes().to_vec()))
+			.collect(),
+		_ => riscv64::scan(code)
+			.into_iter()
+			.map(|f| (f.offset, f.original.to_le_bytes().to_vec()))
+			.collect(),
+	};
+	if sites.is_empty() {
+		let mut msg = String::from("the alternate-flag branch is not in DebugTuple::field:\n");
+		for (i, chunk) in code.chunks(16).take(scan::SCAN_LIMIT / 16).enumerate() {
+			write!(msg, "  {:04x}:", i * 16).unwrap();
+			for b in chunk {
+				write!(msg, " {b:02x}").unwrap();
+			}
+			msg.push('\n');
+		}
+		return Err(Probe::Failed(msg));
+	}
+	Ok(sites)
+}
+
+/// Splits an `ar` archive into the contents of its members.
+fn members(archive: &[u8]) -> Vec<&[u8]> {
+	let mut members = Vec::new();
+	let Some(mut rest) = archive.strip_prefix(b"!<arch>\n") else {
+		return members;
+	};
+	while rest.len() >= 60 {
+		let Some(size) = std::str::from_utf8(&rest[48..58])
+			.ok()
+			.and_then(|s| s.trim().parse::<usize>().ok())
+		else {
+			break;
+		};
+		let Some(data) = rest.get(60..60 + size) else {
+			break;
+		};
+		members.push(data);
+		rest = rest.get(60 + size + size % 2..).unwrap_or_default();
+	}
+	members
+}
+
+/// Returns the code of `DebugTuple::field` if it is in a little-endian ELF object.
+fn find_field(obj: &[u8]) -> Option<&[u8]> {
+	let field = elf::functions(obj).into_iter().find(|f| {
+		let name = std::str::from_utf8(f.name)
+			.ok()
+			.and_then(demangle::demangle);
+		name.is_some_and(|n| demangle::same_path(&n, "core::fmt::builders::DebugTuple::field"))
+	})?;
+	let text = elf::section_offset(obj, field.section)?;
+	obj.get(text + field.value..text + field.value + field.size)
+}
@@ -0,0 +1,8 @@
+//! Prints a tuple without enabling the patch, which the `auto` feature does before `main`.
+
+// Nothing else refers to the crate, which would leave it out altogether.
+use compact_debug as _;
+
+fn main() {
+	println!("{:#?}", (8, 32));
+}
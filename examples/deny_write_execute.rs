@@ -0,0 +1,17 @@
+//! Enables and disables the patch in a process that is refused memory that is writable and
+//! executable at once, like under systemd's `MemoryDenyWriteExecute=yes`, and prints a tuple each
+//! time.
+
+fn main() {
+	// `PR_SET_MDWE` with `PR_MDWE_REFUSE_EXEC_GAIN`, which Linux has since 6.3.
+	#[cfg(target_os = "linux")]
+	if unsafe { libc::prctl(65, 1, 0, 0, 0) } != 0 {
+		println!("unsupported: {}", std::io::Error::last_os_error());
+		return;
+	}
+	let t = (8, 32);
+	unsafe { compact_debug::try_enable(true) }.unwrap();
+	println!("{t:#?}");
+	unsafe { compact_debug::try_enable(false) }.unwrap();
+	println!("{t:#?}");
+}
@@ -0,0 +1,9 @@
+//! Enables the patch and prints a tuple, for checking the patch against a `std` that is linked
+//! dynamically, such as with `-C prefer-dynamic`.
+
+fn main() {
+	let t = (8, 32);
+	unsafe { compact_debug::enable(true) };
+	println!("{:#?}", compact_debug::status().status);
+	println!("{t:#?}");
+}
@@ -0,0 +1,84 @@
+//! Patch for aarch64.
+//!
+//! The `Debug` builders load the formatter's flags and then branch on the alternate bit, either
+//! with `tbz`/`tbnz`, or with `tst` followed by `b.eq`/`b.ne`. The patch makes that test read the
+//! zero register instead of the loaded flags, which always takes the non-pretty path: `tbnz` and
+//! `b.ne` become never taken and `tbz` and `b.eq` always taken, just like a `nop` or `b` would.
+//! Unlike those, it leaves the rest of the instruction intact, so the original is recovered by
+//! putting back the register from the load.
+
+use std::arch::asm;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::scan::SCAN_LIMIT;
+use crate::{PatchError, Site};
+
+#[path = "scan/aarch64.rs"]
+mod scan;
+
+pub use scan::Insn;
+
+/// Finds every test of the alternate flag in a function, which is `len` bytes long if that is known.
+pub unsafe fn find_patch_sites(
+	function: *const u8,
+	len: Option<usize>,
+	name: &'static str,
+) -> Result<Vec<Site>, PatchError> {
+	let code = unsafe { std::slice::from_raw_parts(function, len.unwrap_or(SCAN_LIMIT)) };
+	let found = scan::scan(code);
+	if found.is_empty() {
+		return Err(PatchError::UnsupportedToolchain { function: name });
+	}
+	Ok(found
+		.into_iter()
+		.map(|f| Site {
+			ptr: function.wrapping_add(f.offset) as *mut u32,
+			original: f.original,
+			patched: f.patched,
+		})
+		.collect())
+}
+
+/// Returns where a PLT entry jumps to.
+///
+/// That is `adrp x16`, `ldr x17, [x16, #off]`, `add x16, x16, #off` and `br x17`, possibly preceded
+/// by `bti c`.
+pub unsafe fn follow_stub(function: *const u8) -> Option<*const u8> {
+	const BTI_C: u32 = 0xD503245F;
+	let code = unsafe { std::slice::from_raw_parts(function.cast::<u32>(), 5) };
+	let start = usize::from(code[0] == BTI_C);
+	let [adrp, ldr, _, br] = <[u32; 4]>::try_from(&code[start..start + 4]).ok()?;
+	if adrp & 0x9F00001F != 0x90000010 || ldr & 0xFFC003FF != 0xF9400211 || br != 0xD61F0220 {
+		return None;
+	}
+	let page = ((adrp >> 5 & 0x7FFFF) << 2 | adrp >> 29 & 3) as i32;
+	let page = (page << 11 >> 11) as isize * 0x1000;
+	let pc = function as usize + start * 4;
+	let slot = (pc & !0xFFF).wrapping_add_signed(page) + (ldr >> 10 & 0xFFF) as usize * 8;
+	Some(unsafe { (slot as *const *const u8).read() })
+}
+
+pub unsafe fn load(ptr: *mut Insn) -> Insn {
+	unsafe { AtomicU32::from_ptr(ptr) }.load(Ordering::SeqCst)
+}
+
+/// Stores the instruction at `ptr` by writing it to `via`, which is either the same or an alias,
+/// and then makes sure that all cores will see it.
+///
+/// The data cache is cleaned where the instruction was written, and the instruction cache is
+/// invalidated where it is executed, which the architecture allows to differ.
+pub unsafe fn store(ptr: *mut Insn, via: *mut Insn, insn: Insn) {
+	unsafe {
+		AtomicU32::from_ptr(via).store(insn, Ordering::SeqCst);
+		asm!(
+			"dc cvau, {via}",
+			"dsb ish",
+			"ic ivau, {ptr}",
+			"dsb ish",
+			"isb",
+			ptr = in(reg) ptr,
+			via = in(reg) via,
+			options(nostack, preserves_flags),
+		);
+	}
+}
@@ -0,0 +1,159 @@
+//! Writing to code without ever making it both writable and executable.
+//!
+//! Systems that enforce W^X, such as with SELinux `deny_execmem`, PaX `MPROTECT` or systemd
+//! `MemoryDenyWriteExecute=yes`, refuse to make code writable, but still allow executing a file that
+//! is mapped without write access. So the pages to patch are replaced with a `memfd` holding the
+//! same code, mapped in their place without write access, and mapped once more elsewhere without
+//! execute access. Writes go through the latter, and since both map the same memory, show up in the
+//! code right away, just as atomically as if they were made to the code itself.
+//!
+//! Pages are only replaced the first time they are written to, and stay that way. A process that
+//! forks without `exec` shares them with its child, so patching either affects both.
+//!
+//! A `memfd` lives on tmpfs, which SELinux may not allow executing either. Code is then written
+//! through `/proc/self/mem` instead, which the kernel lets through to memory that is not writable,
+//! without changing how it is mapped. That cannot create code, though, so detours are not possible.
+
+use std::io;
+#[cfg(target_os = "linux")]
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::sync::{Mutex, PoisonError};
+
+/// The pages that have been replaced, and where the alias of each range of them is.
+#[cfg(target_os = "linux")]
+static ALIASES: Mutex<Vec<(Range<usize>, usize)>> = Mutex::new(Vec::new());
+
+/// Returns where to write to change the code at `ptr`, `len` bytes of which must not span pages
+/// that were replaced separately.
+#[cfg(target_os = "linux")]
+pub unsafe fn alias(ptr: *const u8, len: usize) -> io::Result<*mut u8> {
+	let ptr = ptr as usize;
+	let mut aliases = ALIASES.lock().unwrap_or_else(PoisonError::into_inner);
+	let overlapping = aliases
+		.iter()
+		.find(|(pages, _)| pages.start < ptr + len && ptr < pages.end);
+	if let Some((pages, alias)) = overlapping {
+		if pages.start <= ptr && ptr + len <= pages.end {
+			return Ok((alias + (ptr - pages.start)) as *mut u8);
+		}
+		return Err(io::ErrorKind::Unsupported.into());
+	}
+
+	let page = page_size();
+	let start = ptr / page * page;
+	let end = (ptr + len).next_multiple_of(page);
+	let fd = memfd(unsafe { std::slice::from_raw_parts(start as *const u8, end - start) })?;
+	// The alias is mapped first, so that a failure leaves the code as it was.
+	let alias = map(fd.0, None, end - start, libc::PROT_READ | libc::PROT_WRITE)?;
+	if let Err(e) = map(
+		fd.0,
+		Some(start),
+		end - start,
+		libc::PROT_READ | libc::PROT_EXEC,
+	) {
+		unsafe { libc::munmap(alias as *mut libc::c_void, end - start) };
+		return Err(e);
+	}
+	aliases.push((start..end, alias));
+	Ok((alias + (ptr - start)) as *mut u8)
+}
+
+/// Returns where to write to change the code at `ptr`, which is not possible here.
+#[cfg(not(target_os = "linux"))]
+pub unsafe fn alias(_ptr: *const u8, _len: usize) -> io::Result<*mut u8> {
+	Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Writes `bytes` to the code at `ptr` through `/proc/self/mem`.
+///
+/// The kernel copies the page the first time, just as for a write to any private mapping, and
+/// keeps the instruction cache coherent. It may write a byte at a time, though.
+#[cfg(target_os = "linux")]
+pub unsafe fn write(ptr: *const u8, bytes: &[u8]) -> io::Result<()> {
+	use std::os::unix::fs::FileExt;
+
+	let mem = std::fs::OpenOptions::new()
+		.write(true)
+		.open("/proc/self/mem")?;
+	mem.write_all_at(bytes, ptr as u64)
+}
+
+/// Writes `bytes` to the code at `ptr`, which is not possible here.
+#[cfg(not(target_os = "linux"))]
+pub unsafe fn write(_ptr: *const u8, _bytes: &[u8]) -> io::Result<()> {
+	Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Puts `code` in memory that can be executed but not written, where it stays forever.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub fn alloc(code: &[u8]) -> io::Result<*const u8> {
+	let fd = memfd(code)?;
+	let len = code.len().next_multiple_of(page_size());
+	let ptr = map(fd.0, None, len, libc::PROT_READ | libc::PROT_EXEC)?;
+	Ok(ptr as *const u8)
+}
+
+/// Puts `code` in memory that can be executed but not written, which is not possible here.
+#[cfg(all(not(target_os = "linux"), target_arch = "x86_64"))]
+pub fn alloc(_code: &[u8]) -> io::Result<*const u8> {
+	Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn page_size() -> usize {
+	unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// A file descriptor, which is closed when dropped. Mappings of it outlive it.
+#[cfg(target_os = "linux")]
+struct Fd(libc::c_int);
+
+#[cfg(target_os = "linux")]
+impl Drop for Fd {
+	fn drop(&mut self) {
+		unsafe { libc::close(self.0) };
+	}
+}
+
+/// Creates a `memfd` holding `contents`, padded to a whole number of pages.
+#[cfg(target_os = "linux")]
+fn memfd(contents: &[u8]) -> io::Result<Fd> {
+	let fd = unsafe { libc::memfd_create(c"compact-debug".as_ptr(), libc::MFD_CLOEXEC) };
+	if fd < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let fd = Fd(fd);
+	let len = contents.len().next_multiple_of(page_size());
+	if unsafe { libc::ftruncate(fd.0, len as libc::off_t) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let mut written = 0;
+	while written < contents.len() {
+		let rest = &contents[written..];
+		let n = unsafe { libc::write(fd.0, rest.as_ptr().cast(), rest.len()) };
+		if n < 0 {
+			let e = io::Error::last_os_error();
+			if e.kind() != io::ErrorKind::Interrupted {
+				return Err(e);
+			}
+			continue;
+		}
+		written += n as usize;
+	}
+	Ok(fd)
+}
+
+/// Maps a file shared, at `at` if given, which replaces whatever was there.
+#[cfg(target_os = "linux")]
+fn map(fd: libc::c_int, at: Option<usize>, len: usize, prot: libc::c_int) -> io::Result<usize> {
+	let (addr, flags) = match at {
+		Some(at) => (at as *mut libc::c_void, libc::MAP_SHARED | libc::MAP_FIXED),
+		None => (std::ptr::null_mut(), libc::MAP_SHARED),
+	};
+	let ptr = unsafe { libc::mmap(addr, len, prot, flags, fd, 0) };
+	if ptr == libc::MAP_FAILED {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(ptr as usize)
+}
@@ -0,0 +1,27 @@
+//! Enabling the patch before `main`, for the `auto` feature.
+//!
+//! A function in `.init_array` is called by the dynamic loader, or by the C runtime of a static
+//! executable, before `main` runs, which in a test binary is the test harness. Shared libraries get
+//! theirs called when they are loaded. Mach-O and the Windows CRT have sections of their own that
+//! work the same.
+
+#[used]
+#[cfg_attr(target_vendor = "apple", link_section = "__DATA,__mod_init_func")]
+#[cfg_attr(windows, link_section = ".CRT$XCU")]
+#[cfg_attr(
+	not(any(target_vendor = "apple", windows)),
+	link_section = ".init_array"
+)]
+static INIT: extern "C" fn() = init;
+
+extern "C" fn init() {
+	// Unwinding out of here would abort the process, while a failure to enable the patch only means
+	// that `{:#?}` keeps its usual output, which `status` tells about. A malformed variable is a
+	// mistake of whoever set it though, which they would not find out about otherwise.
+	// SAFETY: nothing else can be patching `std` before `main`.
+	let _ = std::panic::catch_unwind(|| {
+		if let Err(e @ crate::PatchError::InvalidEnv { .. }) = unsafe { crate::enable_from_env() } {
+			eprintln!("compact-debug: {e}");
+		}
+	});
+}
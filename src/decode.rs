@@ -0,0 +1,225 @@
+//! Instruction length decoder for x86, both 64-bit and 32-bit.
+//!
+//! This knows just enough about each instruction to tell how long it is, and whether it depends on
+//! where it is placed: everything else about it, such as what it does, is ignored.
+//!
+//! The two differ in a few places: x86_64 has REX prefixes where x86 has `inc`/`dec`, and VEX and
+//! EVEX prefixes that x86 can only tell apart from `les`, `lds` and `bound` by the byte after them.
+
+use crate::arch::scan::modrm_len;
+
+/// The longest an instruction can be.
+const MAX_LEN: usize = 15;
+
+/// What the decoder found out about an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Insn {
+	pub len: usize,
+	/// Whether it has a memory operand relative to `rip`.
+	pub rip_relative: bool,
+	/// For relative branches and calls, the offset of the target from the end of the instruction.
+	pub rel: Option<i32>,
+	/// Whether it is a call through a register or memory, which leaves its return address behind.
+	pub call: bool,
+}
+
+// Only detours care about this, which are only done on x86_64.
+#[cfg(target_arch = "x86_64")]
+impl Insn {
+	/// Whether the instruction works the same no matter where it is placed.
+	pub fn is_relocatable(&self) -> bool {
+		!self.rip_relative && self.rel.is_none()
+	}
+}
+
+/// What follows the opcode.
+#[derive(Clone, Copy)]
+struct Operands {
+	modrm: bool,
+	imm: usize,
+	rel: usize,
+}
+
+const NONE: Operands = Operands {
+	modrm: false,
+	imm: 0,
+	rel: 0,
+};
+const MODRM: Operands = Operands {
+	modrm: true,
+	..NONE
+};
+
+const fn imm(len: usize) -> Operands {
+	Operands { imm: len, ..NONE }
+}
+
+const fn modrm_imm(len: usize) -> Operands {
+	Operands { imm: len, ..MODRM }
+}
+
+const fn rel(len: usize) -> Operands {
+	Operands { rel: len, ..NONE }
+}
+
+const X86_64: bool = cfg!(target_arch = "x86_64");
+
+/// Decodes the instruction at the start of `code`, or returns `None` if it is invalid or not
+/// known.
+pub fn decode(code: &[u8]) -> Option<Insn> {
+	let mut i = 0;
+	let mut opsize = false;
+	let mut addrsize = false;
+	loop {
+		match *code.get(i)? {
+			0x66 => opsize = true,
+			0x67 => addrsize = true,
+			0xF0 | 0xF2 | 0xF3 | 0x26 | 0x2E | 0x36 | 0x3E | 0x64 | 0x65 => {}
+			_ => break,
+		}
+		i += 1;
+	}
+	let rex = match *code.get(i)? {
+		b @ 0x40..=0x4F if X86_64 => {
+			i += 1;
+			b
+		}
+		_ => 0,
+	};
+	let wide = rex & 0x08 != 0;
+	// imm16 or imm32, depending on the operand size
+	let z = if opsize && !wide { 2 } else { 4 };
+
+	let opcode = *code.get(i)?;
+	i += 1;
+	let operands = match opcode {
+		0x0F => {
+			let opcode = *code.get(i)?;
+			i += 1;
+			match opcode {
+				0x38 => {
+					i += 1;
+					MODRM
+				}
+				0x3A => {
+					i += 1;
+					modrm_imm(1)
+				}
+				_ => two_byte(opcode)?,
+			}
+		}
+		0xC4 | 0xC5 | 0x62 if rex == 0 && (X86_64 || *code.get(i)? >> 6 == 3) => {
+			// VEX and EVEX, which carry the map and the opcode after them
+			let (len, map) = match opcode {
+				0xC5 => (1, 1),
+				0xC4 => (2, *code.get(i)? & 0x1F),
+				_ => (3, *code.get(i)? & 0x07),
+			};
+			i += len;
+			let opcode = *code.get(i)?;
+			i += 1;
+			match map {
+				1 => match opcode {
+					0x77 => NONE, // vzeroupper, vzeroall
+					0x70..=0x73 | 0xC2 | 0xC4..=0xC6 => modrm_imm(1),
+					_ => MODRM,
+				},
+				3 => modrm_imm(1),
+				_ => MODRM,
+			}
+		}
+		0x00..=0x3F => match opcode & 7 {
+			0..=3 => MODRM,
+			4 => imm(1),
+			5 => imm(z),
+			_ => return None,
+		},
+		0x40..=0x4F => NONE, // inc, dec
+		0x50..=0x5F => NONE,
+		0x63 => MODRM,
+		0x68 => imm(z),
+		0x69 => modrm_imm(z),
+		0x6A => imm(1),
+		0x6B => modrm_imm(1),
+		0x6C..=0x6F => NONE,
+		0x70..=0x7F => rel(1),
+		0x80 | 0x83 => modrm_imm(1),
+		0x81 => modrm_imm(z),
+		0x84..=0x8F => MODRM,
+		0x90..=0x99 | 0x9B..=0x9F => NONE,
+		0xA0..=0xA3 => imm(match (X86_64, addrsize) {
+			(true, false) => 8,
+			(false, true) => 2,
+			_ => 4,
+		}),
+		0xA4..=0xA7 | 0xAA..=0xAF => NONE,
+		0xA8 => imm(1),
+		0xA9 => imm(z),
+		0xB0..=0xB7 => imm(1),
+		0xB8..=0xBF => imm(if wide { 8 } else { z }),
+		0xC0 | 0xC1 | 0xC6 => modrm_imm(1),
+		0xC4 | 0xC5 | 0x62 => MODRM, // les, lds, bound
+		0xC2 | 0xCA => imm(2),
+		0xC3 | 0xC9 | 0xCB | 0xCC | 0xCF => NONE,
+		0xC7 => modrm_imm(z),
+		0xC8 => imm(3),
+		0xCD => imm(1),
+		0xD0..=0xD3 | 0xD8..=0xDF => MODRM,
+		0xD7 => NONE,
+		0xE0..=0xE3 | 0xEB => rel(1),
+		0xE4..=0xE7 => imm(1),
+		0xE8 | 0xE9 => rel(4),
+		0xEC..=0xEF | 0xF1 | 0xF4 | 0xF5 | 0xF8..=0xFD => NONE,
+		// `test` has an immediate, the rest of the group does not.
+		0xF6 | 0xF7 => {
+			let reg = *code.get(i)? >> 3 & 7;
+			match (reg, opcode) {
+				(0 | 1, 0xF6) => modrm_imm(1),
+				(0 | 1, _) => modrm_imm(z),
+				_ => MODRM,
+			}
+		}
+		0xFE | 0xFF => MODRM,
+		_ => return None,
+	};
+
+	let mut rip_relative = false;
+	let mut call = false;
+	if operands.modrm {
+		let modrm = *code.get(i)?;
+		rip_relative = X86_64 && modrm >> 6 == 0 && modrm & 7 == 5;
+		call = opcode == 0xFF && matches!(modrm >> 3 & 7, 2 | 3);
+		i += modrm_len(&code[i..])?.0;
+	}
+	i += operands.imm;
+	let rel = match operands.rel {
+		0 => None,
+		1 => Some(*code.get(i)? as i8 as i32),
+		_ => Some(i32::from_le_bytes(code.get(i..i + 4)?.try_into().ok()?)),
+	};
+	i += operands.rel;
+	(i <= MAX_LEN && i <= code.len()).then_some(Insn {
+		len: i,
+		rip_relative,
+		rel,
+		call,
+	})
+}
+
+/// The operands of an opcode in the `0F` map.
+fn two_byte(opcode: u8) -> Option<Operands> {
+	Some(match opcode {
+		0x80..=0x8F => rel(4),
+		0x05..=0x09
+		| 0x0B
+		| 0x0E
+		| 0x30..=0x37
+		| 0x77
+		| 0xA0..=0xA2
+		| 0xA8..=0xAA
+		| 0xC8..=0xCF => NONE,
+		0x0F | 0x70..=0x73 | 0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6 => modrm_imm(1),
+		0x04 | 0x0A | 0x0C | 0x24..=0x27 | 0x39 | 0x3B..=0x3F => return None,
+		_ => MODRM,
+	})
+}
@@ -0,0 +1,37 @@
+//! Demangling of Rust symbol names, both legacy and v0, by way of `rustc-demangle`.
+
+/// Demangles a symbol name, legacy or v0, or returns `None` if it is not a Rust one.
+///
+/// The output is that of the alternate form, without hashes and crate disambiguators, such as
+/// `<core::fmt::builders::DebugTuple>::field` for a v0 name.
+pub fn demangle(name: &str) -> Option<String> {
+	rustc_demangle::try_demangle(name)
+		.ok()
+		.map(|name| format!("{name:#}"))
+}
+
+/// Whether two demangled paths name the same item, whether or not an inherent impl is spelled out
+/// as `<T>::f` like v0 does, or as `T::f` like legacy names do.
+pub fn same_path(a: &str, b: &str) -> bool {
+	fn unwrap(path: &str) -> String {
+		let Some(rest) = path.strip_prefix('<') else {
+			return path.to_string();
+		};
+		let mut depth = 1;
+		for (i, c) in rest.char_indices() {
+			match c {
+				'<' => depth += 1,
+				'>' => depth -= 1,
+				_ => {}
+			}
+			if depth == 0 {
+				return match rest[..i].contains(" as ") {
+					true => path.to_string(),
+					false => format!("{}{}", &rest[..i], &rest[i + 1..]),
+				};
+			}
+		}
+		path.to_string()
+	}
+	unwrap(a) == unwrap(b)
+}
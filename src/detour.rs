@@ -0,0 +1,199 @@
+//! Detours for x86_64.
+//!
+//! A detour replaces the entry of a function with a jump to a hook, which takes the same arguments.
+//! The instructions that the jump overwrites are copied to a trampoline, followed by a jump back
+//! into the rest of the function, so that the hook can still call the original through it.
+//!
+//! Copying an instruction elsewhere only works if it does not depend on where it is, so the detour
+//! is refused if any of them is a relative branch, or refers to memory relative to `rip`. The same
+//! goes for the instructions being branched to from later on in the function, since that would land
+//! in the middle of the jump.
+//!
+//! The jump is written with a single 8-byte store, so a thread entering the function sees either
+//! the old or the new entry. A thread that is already past the first instruction would go on in the
+//! middle of the jump though, so when the jump overwrites more than that, the other threads are
+//! stopped while it is written, and those among the instructions are moved to the trampoline, see
+//! [`stop`](crate::stop). That only works on Linux, so elsewhere the detour is refused instead.
+//! A call among them would leave a return address in the middle of the jump that cannot be moved,
+//! so that is refused everywhere. Either way, a detour is only ever attached once, and never
+//! removed.
+
+use std::io;
+#[cfg(target_os = "linux")]
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::decode::decode;
+use crate::scan::SCAN_LIMIT;
+use crate::targets::Function;
+use crate::{resolve, PatchError};
+
+/// The length of the `jmp rel32` to the hook.
+const JMP_LEN: usize = 5;
+
+/// The words that attached detours were written into, and what they held before.
+static ATTACHED: Mutex<Vec<(usize, u64)>> = Mutex::new(Vec::new());
+
+/// A detour that has been prepared, but maybe not attached yet.
+pub struct Detour {
+	/// The aligned word that the jump is written into.
+	word: *mut u64,
+	/// What it holds before.
+	original: u64,
+	/// What to write there.
+	patched: u64,
+	trampoline: *const u8,
+	/// The instructions that the jump overwrites, if there is more than one.
+	#[cfg(target_os = "linux")]
+	moved: Option<Range<usize>>,
+}
+
+// SAFETY: both pointers point into code, which lives for the whole process.
+unsafe impl Send for Detour {}
+unsafe impl Sync for Detour {}
+
+impl Detour {
+	/// Builds the trampoline for a detour from `function` to `hook`, without touching `function`
+	/// yet.
+	///
+	/// # Errors
+	/// Fails with [`PatchError::NotRelocatable`] if the start of the function cannot be moved to the
+	/// trampoline, or the jump to the hook cannot be written over it.
+	pub unsafe fn new(function: Function, hook: *const ()) -> Result<Detour, PatchError> {
+		let refuse = |found: &[u8]| PatchError::NotRelocatable {
+			function: function.name,
+			found: found.to_vec(),
+		};
+		let target = function.ptr;
+		let offset = target as usize % 8;
+		if offset + JMP_LEN > 8 {
+			return Err(refuse(&[]));
+		}
+		let rel = (hook as isize).wrapping_sub(target as isize + JMP_LEN as isize);
+		let rel = i32::try_from(rel).map_err(|_| refuse(&[]))?;
+
+		// Branches back into the part that is overwritten are looked for in the whole function.
+		let size = resolve::size(function).unwrap_or(SCAN_LIMIT);
+		let code = unsafe { std::slice::from_raw_parts(target, size) };
+		let mut len = 0;
+		while len < JMP_LEN {
+			let insn = decode(&code[len..])
+				.ok_or_else(|| refuse(code.get(len..len + 1).unwrap_or(&[])))?;
+			let found = &code[len..len + insn.len];
+			if !insn.is_relocatable() {
+				return Err(refuse(found));
+			}
+			len += insn.len;
+			if insn.call && len < JMP_LEN {
+				return Err(refuse(found));
+			}
+			if cfg!(not(target_os = "linux")) && len < JMP_LEN {
+				return Err(refuse(&[]));
+			}
+		}
+		if let Some((at, n)) = branch_into(code, len) {
+			return Err(refuse(&code[at..at + n]));
+		}
+
+		let word = target.wrapping_sub(offset) as *mut u64;
+		let original = unsafe { word.read() };
+		let mut patched = original.to_le_bytes();
+		patched[offset] = 0xE9; // jmp rel32
+		patched[offset + 1..offset + JMP_LEN].copy_from_slice(&rel.to_le_bytes());
+
+		let mut trampoline = code[..len].to_vec();
+		trampoline.extend([0xFF, 0x25, 0, 0, 0, 0]); // jmp [rip + 0]
+		trampoline.extend((target as u64 + len as u64).to_le_bytes());
+		// The trampoline has to stay around for as long as the detour, which is forever.
+		let ptr = crate::alloc_code(&trampoline)?;
+
+		Ok(Detour {
+			word,
+			original,
+			patched: u64::from_le_bytes(patched),
+			trampoline: ptr,
+			#[cfg(target_os = "linux")]
+			moved: (decode(code).map(|insn| insn.len) != Some(len))
+				.then_some(target as usize..target as usize + len),
+		})
+	}
+
+	/// The original function, as a function pointer of type `F`, which must match it exactly.
+	pub unsafe fn original<F: Copy>(&self) -> F {
+		assert_eq!(size_of::<F>(), size_of::<*const u8>());
+		unsafe { std::mem::transmute_copy(&self.trampoline) }
+	}
+
+	/// Redirects the function to the hook.
+	///
+	/// # Errors
+	/// Fails with [`PatchError::ThreadsNotStopped`] if the other threads have to be stopped for it,
+	/// and cannot be.
+	pub unsafe fn attach(&self) -> Result<(), PatchError> {
+		let writable = unsafe { crate::make_writable(self.word.cast(), 8) }?;
+		let crate::Writable::At { ptr, .. } = writable else {
+			// That may write a byte at a time, which would jump into the middle of nowhere.
+			let e = io::Error::new(
+				io::ErrorKind::Unsupported,
+				"cannot detour through `/proc/self/mem`",
+			);
+			return Err(PatchError::ProtectionDenied(e));
+		};
+		let mut attached = ATTACHED.lock().unwrap_or_else(PoisonError::into_inner);
+		let write =
+			|| unsafe { AtomicU64::from_ptr(ptr.cast()) }.store(self.patched, Ordering::SeqCst);
+		#[cfg(target_os = "linux")]
+		if let Some(moved) = self.moved.clone() {
+			unsafe { crate::stop::stopped(moved, self.trampoline as usize, write) }
+				.map_err(PatchError::ThreadsNotStopped)?;
+		} else {
+			write();
+		}
+		#[cfg(not(target_os = "linux"))]
+		write();
+		attached.push((self.word as usize, self.original));
+		Ok(())
+	}
+}
+
+/// Puts back what detours overwrote in `code`, a copy of the code at `ptr`.
+pub fn undo(ptr: *const u8, code: &mut [u8]) {
+	let attached = ATTACHED.lock().unwrap_or_else(PoisonError::into_inner);
+	for &(word, original) in attached.iter() {
+		for (i, b) in original.to_le_bytes().into_iter().enumerate() {
+			if let Some(c) = (word + i)
+				.checked_sub(ptr as usize)
+				.and_then(|at| code.get_mut(at))
+			{
+				*c = b;
+			}
+		}
+	}
+}
+
+/// Finds a branch that lands in the first `len` bytes of the function, other than at its start,
+/// returning its offset and length.
+///
+/// The function is decoded up to the `ret` that ends it. Early returns are told apart by a branch
+/// jumping past them.
+fn branch_into(code: &[u8], len: usize) -> Option<(usize, usize)> {
+	let mut end = 0;
+	let mut i = 0;
+	while let Some(insn) = decode(&code[i..]) {
+		if let Some(rel) = insn.rel {
+			let target = (i + insn.len).wrapping_add_signed(rel as isize);
+			if (1..len).contains(&target) {
+				return Some((i, insn.len));
+			}
+			if target < code.len() {
+				end = end.max(target);
+			}
+		}
+		if code[i] == 0xC3 && i >= end {
+			break;
+		}
+		i += insn.len;
+	}
+	None
+}
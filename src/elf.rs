@@ -0,0 +1,186 @@
+//! Just enough of ELF to find functions by name.
+//!
+//! This only reads the section headers and symbol tables of little-endian objects, both 32-bit and
+//! 64-bit, and is shared with the build script, which looks through object files, while the crate
+//! looks through the executables and shared libraries that are loaded. The crate also reads the
+//! notes, for the build ID that tells whether a file is still the one an image was loaded from.
+
+/// A symbol that is defined in some section.
+pub struct Symbol<'a> {
+	/// The mangled name.
+	pub name: &'a [u8],
+	/// The address, or in object files, the offset into its section.
+	pub value: usize,
+	pub size: usize,
+	/// The index of its section.
+	pub section: usize,
+}
+
+/// The header of an object.
+struct Header<'a> {
+	obj: &'a [u8],
+	wide: bool,
+	shoff: usize,
+	shentsize: usize,
+	shnum: usize,
+}
+
+/// A section header, with only the fields that are of interest.
+struct Section {
+	ty: usize,
+	offset: usize,
+	size: usize,
+	link: usize,
+	align: usize,
+	entsize: usize,
+}
+
+const SHT_SYMTAB: usize = 2;
+const SHT_NOTE: usize = 7;
+const SHT_DYNSYM: usize = 11;
+const STT_FUNC: u8 = 2;
+const NT_GNU_BUILD_ID: usize = 3;
+
+impl<'a> Header<'a> {
+	fn parse(obj: &'a [u8]) -> Option<Header<'a>> {
+		if !obj.starts_with(b"\x7FELF") {
+			return None;
+		}
+		let wide = match obj.get(4..6)? {
+			[1, 1] => false,
+			[2, 1] => true,
+			_ => return None,
+		};
+		let (shoff, shentsize, shnum) = match wide {
+			true => (0x28, 0x3A, 0x3C),
+			false => (0x20, 0x2E, 0x30),
+		};
+		Some(Header {
+			obj,
+			wide,
+			shoff: int(obj, shoff, if wide { 8 } else { 4 })?,
+			shentsize: int(obj, shentsize, 2)?,
+			shnum: int(obj, shnum, 2)?,
+		})
+	}
+
+	fn int(&self, at: usize, len: usize) -> Option<usize> {
+		int(self.obj, at, len)
+	}
+
+	fn word(&self) -> usize {
+		if self.wide {
+			8
+		} else {
+			4
+		}
+	}
+
+	fn section(&self, i: usize) -> Option<Section> {
+		if i >= self.shnum {
+			return None;
+		}
+		let word = self.word();
+		let at = self.shoff + i * self.shentsize;
+		let off = at + 8 + 2 * word;
+		Some(Section {
+			ty: self.int(at + 4, 4)?,
+			offset: self.int(off, word)?,
+			size: self.int(off + word, word)?,
+			link: self.int(off + 2 * word, 4)?,
+			align: self.int(off + 2 * word + 8, word)?,
+			entsize: self.int(off + 2 * word + 8 + word, word)?,
+		})
+	}
+
+	fn symbol(&self, at: usize, strtab: usize) -> Option<Symbol<'a>> {
+		// st_value and st_size come before st_info and st_shndx on 32-bit, and after on 64-bit
+		let (value, size, info, section) = match self.wide {
+			true => (at + 8, at + 16, at + 4, at + 6),
+			false => (at + 4, at + 8, at + 12, at + 14),
+		};
+		let word = self.word();
+		let (value, size) = (self.int(value, word)?, self.int(size, word)?);
+		let (info, section) = (self.int(info, 1)?, self.int(section, 2)?);
+		if info as u8 & 0xF != STT_FUNC || section == 0 || section >= self.shnum {
+			return None;
+		}
+		let name = strtab + self.int(at, 4)?;
+		let len = self.obj.get(name..)?.iter().position(|&b| b == 0)?;
+		Some(Symbol {
+			name: &self.obj[name..name + len],
+			value,
+			size,
+			section,
+		})
+	}
+}
+
+/// Reads a little-endian integer of `len` bytes.
+fn int(obj: &[u8], at: usize, len: usize) -> Option<usize> {
+	let mut bytes = [0; 8];
+	bytes[..len].copy_from_slice(obj.get(at..at.checked_add(len)?)?);
+	usize::try_from(u64::from_le_bytes(bytes)).ok()
+}
+
+/// Lists the functions defined in an object, from both its static and its dynamic symbol table.
+///
+/// Anything that is not a little-endian ELF object has none.
+pub fn functions(obj: &[u8]) -> Vec<Symbol<'_>> {
+	let mut symbols = Vec::new();
+	let Some(header) = Header::parse(obj) else {
+		return symbols;
+	};
+	for i in 0..header.shnum {
+		let Some(table) = header.section(i) else {
+			break;
+		};
+		if !matches!(table.ty, SHT_SYMTAB | SHT_DYNSYM) || table.entsize == 0 {
+			continue;
+		}
+		let Some(strtab) = header.section(table.link) else {
+			continue;
+		};
+		let end = table.offset.saturating_add(table.size).min(obj.len());
+		for at in (table.offset..end).step_by(table.entsize) {
+			symbols.extend(header.symbol(at, strtab.offset));
+		}
+	}
+	symbols
+}
+
+/// Returns where in the object a section starts.
+pub fn section_offset(obj: &[u8], section: usize) -> Option<usize> {
+	Some(Header::parse(obj)?.section(section)?.offset)
+}
+
+/// Returns the build ID of an object, from its sections, if it has one.
+pub fn build_id(obj: &[u8]) -> Option<&[u8]> {
+	let header = Header::parse(obj)?;
+	(0..header.shnum)
+		.map_while(|i| header.section(i))
+		.filter(|section| section.ty == SHT_NOTE)
+		.find_map(|section| {
+			let notes = obj.get(section.offset..section.offset.checked_add(section.size)?)?;
+			build_id_in(notes, section.align)
+		})
+}
+
+/// Returns the build ID among `notes`, each of which is aligned to `align` bytes, such as those of
+/// a segment of an image that is loaded.
+pub fn build_id_in(notes: &[u8], align: usize) -> Option<&[u8]> {
+	let align = align.max(4);
+	let mut at = 0;
+	while at < notes.len() {
+		let (namesz, descsz) = (int(notes, at, 4)?, int(notes, at + 4, 4)?);
+		let name = at + 12;
+		let desc = name.checked_add(namesz)?.next_multiple_of(align);
+		let next = desc.checked_add(descsz)?.next_multiple_of(align);
+		if int(notes, at + 8, 4)? == NT_GNU_BUILD_ID && notes.get(name..desc)?.starts_with(b"GNU\0")
+		{
+			return notes.get(desc..desc + descsz);
+		}
+		at = next;
+	}
+	None
+}
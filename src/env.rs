@@ -0,0 +1,52 @@
+//! Parsing of the `COMPACT_DEBUG` environment variable, see
+//! [`enable_from_env`](crate::enable_from_env).
+
+use crate::Targets;
+
+/// What the variable asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Config {
+	/// The targets to enable, all others being disabled, or `None` if none were listed.
+	pub targets: Option<Targets>,
+	/// The width to set, if any.
+	pub max_width: Option<usize>,
+}
+
+/// Parses a comma-separated list of `off`, `tuples`, `structs`, `lists`, `maps`, `all` and
+/// `width=N`, returning what is wrong with it if it is malformed.
+pub fn parse(value: &str) -> Result<Config, String> {
+	let mut config = Config::default();
+	let mut off = false;
+	for item in value.split(',').map(str::trim) {
+		let target = match item {
+			"off" => {
+				off = true;
+				Targets::empty()
+			}
+			"tuples" => Targets::TUPLE,
+			"structs" => Targets::STRUCT,
+			"lists" => Targets::LIST,
+			"maps" => Targets::MAP,
+			"all" => Targets::all(),
+			"" => return Err("an item is empty".to_string()),
+			_ => {
+				let Some(width) = item.strip_prefix("width=") else {
+					return Err(format!(
+						"`{item}` is not one of `off`, `tuples`, `structs`, `lists`, `maps`, `all` \
+						 or `width=N`"
+					));
+				};
+				let width = width
+					.parse()
+					.map_err(|_| format!("`{width}` is not a width"))?;
+				config.max_width = Some(width);
+				continue;
+			}
+		};
+		config.targets = Some(config.targets.unwrap_or_default() | target);
+	}
+	if off && config.targets != Some(Targets::empty()) {
+		return Err("`off` cannot be combined with targets".to_string());
+	}
+	Ok(config)
+}
@@ -0,0 +1,200 @@
+//! The other copies of `std` in the process.
+//!
+//! Every executable and shared library that links `std` statically, such as a `cdylib` plugin, has
+//! its own copy of the `Debug` builders, and patching one of them does nothing for the others. The
+//! loaded images are listed with `dl_iterate_phdr`, and the builders in each are looked up by name
+//! in the symbol tables of the file it was loaded from, since those of `std` are not exported.
+//!
+//! The file is opened through `/proc/self/exe` for the executable, which still leads to it when it
+//! has been replaced on disk since. A shared library that has been replaced can only be told apart
+//! by its build ID, if it has one, and its file is then left unread.
+//!
+//! Images that have been stripped are searched for code that looks like the copy in this crate
+//! instead, which only works if they were built with the same toolchain. Such code has to match
+//! for the most part, since it refers to other code and data at other distances, and contain the
+//! branches to patch at the same offsets.
+
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::elf::{self, Symbol};
+use crate::targets::Function;
+use crate::{resolve, Site};
+
+/// How far apart functions may start, at the least.
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+const FUNCTION_ALIGN: usize = 16;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+const FUNCTION_ALIGN: usize = 2;
+
+/// An executable or shared library loaded in the process.
+pub struct Image {
+	/// The file it was loaded from.
+	pub path: PathBuf,
+	/// Where to read the file from, which is `path` unless that may lead to another file.
+	file: PathBuf,
+	/// The build ID that it was loaded with, if it has one.
+	build_id: Option<Vec<u8>>,
+	/// What it was loaded at, relative to the addresses in the file.
+	pub base: usize,
+	/// Where its code is.
+	code: Vec<Range<usize>>,
+}
+
+/// Lists the images that are loaded, starting with the executable.
+#[cfg(target_os = "linux")]
+pub fn images() -> Vec<Image> {
+	use std::ffi::CStr;
+	use std::os::unix::ffi::OsStrExt;
+
+	unsafe extern "C" fn callback(
+		info: *mut libc::dl_phdr_info,
+		_size: libc::size_t,
+		images: *mut libc::c_void,
+	) -> libc::c_int {
+		let info = unsafe { &*info };
+		let images = unsafe { &mut *images.cast::<Vec<Image>>() };
+		let phdrs = unsafe { std::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum.into()) };
+		let base = info.dlpi_addr as usize;
+		let code = phdrs
+			.iter()
+			.filter(|p| {
+				p.p_type == libc::PT_LOAD
+					&& p.p_flags & (libc::PF_R | libc::PF_X) == libc::PF_R | libc::PF_X
+			})
+			.map(|p| base + p.p_vaddr as usize..base + (p.p_vaddr + p.p_memsz) as usize)
+			.collect();
+		let build_id = phdrs
+			.iter()
+			.filter(|p| p.p_type == libc::PT_NOTE)
+			.find_map(|p| {
+				let ptr = (base + p.p_vaddr as usize) as *const u8;
+				let notes = unsafe { std::slice::from_raw_parts(ptr, p.p_memsz as usize) };
+				elf::build_id_in(notes, p.p_align as usize)
+			})
+			.map(<[u8]>::to_vec);
+		let name = match info.dlpi_name.is_null() {
+			true => &[][..],
+			false => unsafe { CStr::from_ptr(info.dlpi_name) }.to_bytes(),
+		};
+		let (path, file) = match name {
+			[] => (
+				std::env::current_exe().unwrap_or_else(|_| "/proc/self/exe".into()),
+				"/proc/self/exe".into(),
+			),
+			name => {
+				let path = PathBuf::from(std::ffi::OsStr::from_bytes(name));
+				(path.clone(), path)
+			}
+		};
+		images.push(Image {
+			path,
+			file,
+			build_id,
+			base,
+			code,
+		});
+		0
+	}
+
+	let mut images = Vec::new();
+	unsafe { libc::dl_iterate_phdr(Some(callback), (&mut images as *mut Vec<Image>).cast()) };
+	images
+}
+
+/// Lists the images that are loaded, which is not possible here.
+#[cfg(not(target_os = "linux"))]
+pub fn images() -> Vec<Image> {
+	Vec::new()
+}
+
+impl Image {
+	/// Whether an address is in the code of this image.
+	pub fn contains(&self, ptr: usize) -> bool {
+		self.code.iter().any(|range| range.contains(&ptr))
+	}
+
+	/// Reads the file the image was loaded from, or nothing if that is not possible, or if it is
+	/// not the same file anymore.
+	pub fn read(&self) -> Vec<u8> {
+		let file = std::fs::read(&self.file).unwrap_or_default();
+		match &self.build_id {
+			Some(id) if elf::build_id(&file) != Some(id) => Vec::new(),
+			_ => file,
+		}
+	}
+
+	/// Finds the copy of one of the functions of this crate's `std` in this image, given the
+	/// functions in its file, along with its size if that is known.
+	pub unsafe fn find(
+		&self,
+		functions: &[Symbol],
+		function: Function,
+	) -> Option<(Function, Option<usize>)> {
+		let by_name = resolve::in_image(self, functions, &function.path());
+		let (ptr, size) = match by_name.first() {
+			Some(definition) => (definition.address, Some(definition.size)),
+			// What is found looks the same as `function`, so it is most likely as long.
+			None => (unsafe { self.search(function) }?, resolve::size(function)),
+		};
+		let function = Function {
+			ptr: ptr as *const u8,
+			name: function.name,
+		};
+		Some((function, size.filter(|&size| size != 0)))
+	}
+
+	/// Searches the code of the image for something that looks like `function`.
+	unsafe fn search(&self, function: Function) -> Option<usize> {
+		// Candidates are scanned as far as `function` is, since the scan may otherwise run on into
+		// whatever follows them.
+		let size = resolve::size(function);
+		let offsets = unsafe { site_offsets(function, size) }?;
+		let len = offsets.last()? + size_of::<crate::arch::Insn>();
+		#[cfg_attr(not(target_arch = "x86_64"), allow(unused_mut))]
+		let mut pattern = unsafe { std::slice::from_raw_parts(function.ptr, len) }.to_vec();
+		#[cfg(target_arch = "x86_64")]
+		crate::detour::undo(function.ptr, &mut pattern);
+
+		let mut found = None;
+		for range in &self.code {
+			let start = range.start.next_multiple_of(FUNCTION_ALIGN);
+			for ptr in (start..range.end.saturating_sub(len)).step_by(FUNCTION_ALIGN) {
+				let code = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) };
+				if code[..8] != pattern[..8] {
+					continue;
+				}
+				let same = code.iter().zip(&pattern).filter(|(a, b)| a == b).count();
+				let candidate = Function {
+					ptr: ptr as *const u8,
+					name: function.name,
+				};
+				if same * 4 < len * 3
+					|| unsafe { site_offsets(candidate, size) }.as_ref() != Some(&offsets)
+				{
+					continue;
+				}
+				if found.replace(ptr).is_some() {
+					return None;
+				}
+			}
+		}
+		found
+	}
+}
+
+/// Returns where the branches to patch are in a function, which is `size` bytes long if that is
+/// known, as offsets into it.
+unsafe fn site_offsets(function: Function, size: Option<usize>) -> Option<Vec<usize>> {
+	let sites = match size {
+		Some(size) => unsafe { Site::locate_sized(function, size) },
+		None => unsafe { Site::locate(function) },
+	}
+	.ok()?;
+	Some(
+		sites
+			.iter()
+			.map(|s| s.ptr as usize - function.ptr as usize)
+			.collect(),
+	)
+}
@@ -53,38 +53,1209 @@
 //! ])), Address(30016)),
 //! ```
 //!
-//! This crate currently only supports x86_64 architecture.
+//! On x86_64, a tuple whose content, from its opening to its closing parenthesis, would end up wider
+//! than 100 characters keeps its usual layout instead, no matter how far it is indented or how long
+//! its name is. The limit can be changed with `set_max_width`, and `set_tuple_policy` restricts the
+//! patch to tuple structs and variants such as `Address(30016)`, or to tuples such as `(1, 2)`, leaving
+//! the others in their usual layout.
+//!
+//! Otherwise, tuples come out just like with `{:?}`: one with a single field keeps the comma that
+//! tells `(5,)` apart from `5`, and one without fields is printed as its name alone, such as `Unit`.
+//!
+//! Structs can be given the same treatment with `enable_with(Targets::TUPLE | Targets::STRUCT)`,
+//! which prints `B { x: 8, y: 32 }` as is rather than spread over four lines. Lists and sets are
+//! covered by `Targets::LIST` and maps by `Targets::MAP`; each target can be picked on its own, and
+//! the ones left out keep their usual layout.
+//!
+//! This crate currently only supports the x86_64, x86, aarch64 and riscv64 architectures. On other
+//! architectures it still builds, but enabling the patch fails with an error.
+//!
+//! On the supported ones, the build script checks that the patch applies to the `std` of the
+//! toolchain in use, and fails the build if it does not. The toolchains it is known to work with are
+//! listed by `supported_toolchains()`.
+//!
+//! Once patched, a few values are formatted to check that they come out as they should, and if any
+//! does not, the patch is undone and enabling it fails.
+//!
+//! The patch works with `std` linked statically as well as dynamically, such as with
+//! `-C prefer-dynamic`.
+//!
+//! Plugins and other shared libraries that link `std` statically each have a copy of their own, which
+//! `enable` leaves alone. On Linux, `enable_all_images` patches all of them at once.
+//!
+//! The builders are looked up in the symbol tables of the loaded images, so that copies that LTO left
+//! behind under their own names are patched too. The same lookup is available as `definitions`, which
+//! lists every function with a given path, such as `<core::fmt::builders::DebugTuple>::field`.
+//!
+//! Derived impls go through functions in `Formatter` that have copies of `DebugTuple` and `DebugStruct`
+//! inlined into them, which are patched along with them. With LTO, those may in turn be inlined into the
+//! impls, which puts them out of reach; `status().unpatched` lists the ones that still print tuples over
+//! several lines. LTO may just as well give the impls of `std` itself, such as those of tuples and
+//! `Option`, copies of the builders of their own, which the check after patching catches, so that
+//! enabling the patch fails rather than leaving most output as it was.
+//!
+//! Code is patched by making it writable for a moment. On systems that refuse memory that is writable
+//! and executable at once, such as with SELinux `deny_execmem` or systemd `MemoryDenyWriteExecute=yes`,
+//! the code is instead replaced with an identical copy that is mapped twice, once executable and once
+//! writable, which is picked automatically on Linux. Where SELinux does not allow executing that copy
+//! either, the code is written through `/proc/self/mem`, which cannot detour functions, and so leaves
+//! tuples without a maximum width.
+//!
+//! `enable_from_env` enables the patch as the `COMPACT_DEBUG` environment variable says, such as
+//! `COMPACT_DEBUG=off`, `COMPACT_DEBUG=all` or `COMPACT_DEBUG=tuples,structs,width=80`, and like
+//! `enable(true)` does if it is unset. A malformed value is reported as an error.
+//!
+//! With the `auto` feature, the patch is enabled before `main`, and before the test harness in test
+//! binaries, as `enable_from_env` does, without calling it. A malformed `COMPACT_DEBUG` is reported on
+//! stderr. A crate that does not otherwise refer to this one has to do so with
+//! `use compact_debug as _;` for it to be linked in.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+mod alias;
+#[cfg_attr(any(target_arch = "x86_64", target_arch = "x86"), path = "x86.rs")]
+#[cfg_attr(target_arch = "aarch64", path = "aarch64.rs")]
+#[cfg_attr(target_arch = "riscv64", path = "riscv64.rs")]
+#[cfg_attr(
+	not(any(
+		target_arch = "x86_64",
+		target_arch = "x86",
+		target_arch = "aarch64",
+		target_arch = "riscv64",
+	)),
+	path = "unsupported.rs"
+)]
+mod arch;
+// Not in this crate's own tests, which expect to start out with the patch disabled.
+#[cfg(all(feature = "auto", not(test)))]
+mod auto;
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+mod decode;
+mod demangle;
+#[cfg(target_arch = "x86_64")]
+mod detour;
+// Some of it is only there for the build script.
+#[allow(dead_code)]
+mod elf;
+mod env;
+mod images;
+mod resolve;
+// Only the architectures that are patched scan anything.
+#[cfg_attr(
+	not(any(
+		target_arch = "x86_64",
+		target_arch = "x86",
+		target_arch = "aarch64",
+		target_arch = "riscv64",
+	)),
+	allow(dead_code)
+)]
+mod scan;
+mod selftest;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod stop;
+mod targets;
+mod toolchains;
+#[cfg(target_arch = "x86_64")]
+mod width;
+
+pub use resolve::{definitions, Definition};
+use targets::Function;
+pub use targets::Targets;
+pub use toolchains::Toolchain;
+
+include!(concat!(env!("OUT_DIR"), "/sites.rs"));
+
+/// The instruction to flip, and what it holds in each state.
+///
+/// What an instruction is depends on the architecture, but it is always replaced with atomic
+/// stores, so that a thread concurrently executing the function sees either the old or the new
+/// instruction, or at worst a mix of both that behaves like one of them.
+struct Site {
+	ptr: *mut arch::Insn,
+	original: arch::Insn,
+	patched: arch::Insn,
+}
+
+// SAFETY: `ptr` points into code, which lives for the whole process, and is only accessed
+// atomically.
+unsafe impl Send for Site {}
+unsafe impl Sync for Site {}
+
+impl Site {
+	/// Finds all sites in a function, of which there is at least one.
+	///
+	/// Where the branch in `DebugTuple::field` is may already be known, so if it is not there now,
+	/// something must have changed it since, and what is there instead is reported.
+	unsafe fn locate(function: Function) -> Result<Vec<Site>, PatchError> {
+		// Without its size, the scan may run on into the functions that follow.
+		let size = resolve::size(function);
+		let result = unsafe { arch::find_patch_sites(function.ptr, size, function.name) };
+		match (result, known_field_site()) {
+			(
+				Err(PatchError::UnsupportedToolchain { function: name }),
+				Some((offset, expected)),
+			) if name == "DebugTuple::field" => {
+				let ptr = function.ptr.wrapping_add(offset);
+				let found = unsafe { std::slice::from_raw_parts(ptr, expected[0].len()) };
+				Err(PatchError::UnexpectedBytes {
+					function: name,
+					found: found.to_vec(),
+					expected,
+				})
+			}
+			(result, _) => result,
+		}
+	}
+
+	/// Finds all sites in a function that is `size` bytes long, such as one of the hosts of a
+	/// target, see [`Targets::hosts`], which are larger than the builders and have to be scanned as
+	/// a whole.
+	unsafe fn locate_sized(function: Function, size: usize) -> Result<Vec<Site>, PatchError> {
+		unsafe { arch::find_patch_sites(function.ptr, Some(size), function.name) }
+	}
+
+	/// Finds all sites in the functions of a single target.
+	///
+	/// Functions with identical code may be merged by the linker, so the same site is only returned
+	/// once.
+	unsafe fn locate_all(target: Targets) -> Result<Vec<Site>, PatchError> {
+		let mut sites = Vec::new();
+		for function in target.functions() {
+			sites.extend(unsafe { Site::locate(function) }?);
+			// Copies that LTO left behind are only found by name, and may not look the same.
+			for copy in resolve::copies(function) {
+				sites.extend(unsafe { Site::locate(copy) }.unwrap_or_default());
+			}
+		}
+		sites.sort_by_key(|s| s.ptr);
+		sites.dedup_by_key(|s| s.ptr);
+		Ok(sites)
+	}
+
+	/// Finds all sites in the hosts of a single target, see [`Targets::hosts`], leaving out those
+	/// that do not look as expected.
+	unsafe fn locate_hosts(target: Targets) -> Vec<Site> {
+		let mut sites = Vec::new();
+		for (host, size) in resolve::hosts(target) {
+			sites.extend(unsafe { Site::locate_sized(host, size) }.unwrap_or_default());
+		}
+		sites.sort_by_key(|s| s.ptr);
+		sites.dedup_by_key(|s| s.ptr);
+		sites
+	}
+
+	unsafe fn state(&self) -> PatchState {
+		if unsafe { arch::load(self.ptr) } == self.patched {
+			PatchState::Enabled
+		} else {
+			PatchState::Disabled
+		}
+	}
+
+	unsafe fn write(&self, state: PatchState) -> Result<(), PatchError> {
+		let insn = match state {
+			PatchState::Enabled => self.patched,
+			PatchState::Disabled => self.original,
+		};
+		let len = std::mem::size_of::<arch::Insn>();
+		match unsafe { make_writable(self.ptr.cast(), len) }? {
+			Writable::At { ptr, .. } => unsafe { arch::store(self.ptr, ptr.cast(), insn) },
+			// Written a byte at a time, an instruction longer than that only briefly tests some other
+			// register, just like when riscv64 stores one in halves.
+			Writable::Mem => {
+				let bytes =
+					unsafe { std::slice::from_raw_parts(std::ptr::from_ref(&insn).cast(), len) };
+				unsafe { alias::write(self.ptr.cast(), bytes) }
+					.map_err(|e| refused("`/proc/self/mem`", e))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Where the branch in `DebugTuple::field` is known to be, and what it originally holds, either from
+/// the table of known toolchains or else from the build script.
+fn known_field_site() -> Option<(usize, &'static [&'static [u8]])> {
+	match supported_toolchains().iter().find(|t| t.is_current()) {
+		Some(t) => Some((t.offset, std::slice::from_ref(&t.original))),
+		None => Some((*FIELD_OFFSETS.first()?, FIELD_ORIGINAL)),
+	}
+}
+
+/// Whether code is written through an alias, which is decided by the first write, see [`alias`].
+static ALIASED: AtomicBool = AtomicBool::new(false);
+
+/// Whether code is written through `/proc/self/mem`, which is decided by the first write through an
+/// alias, see [`alias::write`].
+static THROUGH_MEM: AtomicBool = AtomicBool::new(false);
+
+/// Code that can be written to for as long as this lives.
+enum Writable {
+	/// At `ptr`, which is either the code itself or an alias of it.
+	At {
+		ptr: *mut u8,
+		_prot: Option<region::ProtectGuard>,
+	},
+	/// Only through [`alias::write`].
+	Mem,
+}
+
+/// Makes code writable until the returned guard is dropped.
+///
+/// The code is made writable in place if the system allows code that is writable and executable at
+/// once. Otherwise, the first refusal makes this and all later writes go through an alias, which
+/// is not executable, and if that is refused too, through `/proc/self/mem`.
+unsafe fn make_writable(ptr: *const u8, len: usize) -> Result<Writable, PatchError> {
+	if !ALIASED.load(Ordering::Relaxed) {
+		match unsafe {
+			region::protect_with_handle(ptr, len, region::Protection::READ_WRITE_EXECUTE)
+		} {
+			Ok(prot) => {
+				return Ok(Writable::At {
+					ptr: ptr.cast_mut(),
+					_prot: Some(prot),
+				})
+			}
+			Err(region::Error::SystemCall(e)) if is_refusal(&e) => {
+				ALIASED.store(true, Ordering::Relaxed);
+			}
+			Err(e) => return Err(protection_denied(e)),
+		}
+	}
+	if !THROUGH_MEM.load(Ordering::Relaxed) {
+		match unsafe { alias::alias(ptr, len) } {
+			Ok(ptr) => return Ok(Writable::At { ptr, _prot: None }),
+			Err(e) if is_refusal(&e) => THROUGH_MEM.store(true, Ordering::Relaxed),
+			Err(e) => return Err(refused("an alias", e)),
+		}
+	}
+	Ok(Writable::Mem)
+}
+
+/// Puts code in memory that can be executed, where it stays forever, such as the trampolines of
+/// detours.
+///
+/// The memory is only writable until the code is copied into it, and never at the same time as it
+/// is executable.
+#[cfg(target_arch = "x86_64")]
+fn alloc_code(code: &[u8]) -> Result<*const u8, PatchError> {
+	if !ALIASED.load(Ordering::Relaxed) {
+		let result =
+			region::alloc(code.len(), region::Protection::READ_WRITE).and_then(|mut alloc| {
+				let ptr = alloc.as_mut_ptr::<u8>();
+				unsafe { ptr.copy_from_nonoverlapping(code.as_ptr(), code.len()) };
+				unsafe { region::protect(ptr, code.len(), region::Protection::READ_EXECUTE) }?;
+				std::mem::forget(alloc);
+				Ok(ptr)
+			});
+		match result {
+			Ok(ptr) => return Ok(ptr),
+			Err(region::Error::SystemCall(e)) if is_refusal(&e) => {
+				ALIASED.store(true, Ordering::Relaxed);
+			}
+			Err(e) => return Err(protection_denied(e)),
+		}
+	}
+	alias::alloc(code).map_err(|e| refused("an alias", e))
+}
+
+/// Whether an error is the system refusing memory that is writable and executable at once.
+///
+/// That is `EACCES` for SELinux and PaX, and `EPERM` for seccomp, such as systemd uses.
+fn is_refusal(e: &io::Error) -> bool {
+	e.kind() == io::ErrorKind::PermissionDenied
+}
+
+/// Reports that no way of writing code worked, the last of which is `last`.
+fn refused(last: &str, e: io::Error) -> PatchError {
+	let message = format!("writable and executable memory is refused, and so is {last}: {e}");
+	PatchError::ProtectionDenied(io::Error::new(e.kind(), message))
+}
+
+fn protection_denied(e: region::Error) -> PatchError {
+	match e {
+		region::Error::SystemCall(e) => PatchError::ProtectionDenied(e),
+		e => PatchError::ProtectionDenied(io::Error::other(e)),
+	}
+}
+
+/// Whether the patch is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PatchState {
+	/// `DebugTuple` is printed the way `std` intends.
+	Disabled,
+	/// `DebugTuple` is printed on a single line.
+	Enabled,
+}
+
+impl std::ops::Not for PatchState {
+	type Output = PatchState;
+	fn not(self) -> PatchState {
+		match self {
+			PatchState::Disabled => PatchState::Enabled,
+			PatchState::Enabled => PatchState::Disabled,
+		}
+	}
+}
+
+impl From<bool> for PatchState {
+	fn from(on: bool) -> Self {
+		if on {
+			PatchState::Enabled
+		} else {
+			PatchState::Disabled
+		}
+	}
+}
+
+/// Why the patch could not be applied.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PatchError {
+	/// The alternate-flag test was found, but it is not followed by a recognized branch.
+	UnexpectedBytes {
+		/// The function that was searched.
+		function: &'static str,
+		/// The bytes following the test.
+		found: Vec<u8>,
+		/// The opcodes that were expected there.
+		expected: &'static [&'static [u8]],
+	},
+	/// The code could not be made writable, for example due to SELinux or seccomp, and could not be
+	/// written through an alias or `/proc/self/mem` either.
+	ProtectionDenied(io::Error),
+	/// The crate does not know how to patch code on this architecture.
+	UnsupportedArch,
+	/// The function does not contain anything resembling the branch, which most likely means `std`
+	/// changed something internally, or the compiler found a better way to optimize it.
+	UnsupportedToolchain {
+		/// The function that was searched.
+		function: &'static str,
+	},
+	/// Something that looks like the branch was found, but decoding the code around it showed that
+	/// it is something else.
+	Unverified {
+		/// The function that was searched.
+		function: &'static str,
+		/// What gave it away.
+		reason: &'static str,
+		/// The instructions around it, one per line.
+		listing: String,
+	},
+	/// The patch failed on a toolchain that it is not known to work with.
+	UnsupportedRelease {
+		/// The release of `rustc`, as reported by `rustc -vV`.
+		release: &'static str,
+		/// What went wrong.
+		cause: Box<PatchError>,
+	},
+	/// A function cannot be detoured, because an instruction at its start only works where it is,
+	/// such as a branch, or because there is no room to write a jump over it.
+	NotRelocatable {
+		/// The function that was to be detoured.
+		function: &'static str,
+		/// The instruction that cannot be moved elsewhere, if that is the reason.
+		found: Vec<u8>,
+	},
+	/// The other threads could not be stopped while a detour was attached, such as because one of
+	/// them blocks signals.
+	ThreadsNotStopped(io::Error),
+	/// The patch was applied, but a value formatted with it did not come out as it should, so it
+	/// was undone again.
+	SelfTestFailed {
+		/// What the value should have been formatted as.
+		expected: &'static str,
+		/// What it was formatted as.
+		found: String,
+	},
+	/// The `COMPACT_DEBUG` environment variable does not say what to enable, see
+	/// [`enable_from_env`].
+	InvalidEnv {
+		/// The value of the variable.
+		value: String,
+		/// What is wrong with it.
+		reason: String,
+	},
+}
+
+impl fmt::Display for PatchError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PatchError::UnexpectedBytes {
+				function,
+				found,
+				expected,
+			} => {
+				write!(
+					f,
+					"{function} is not as expected: found {}, expected ",
+					Hex(found)
+				)?;
+				for (i, e) in expected.iter().enumerate() {
+					if i != 0 {
+						f.write_str(" or ")?;
+					}
+					write!(f, "{}", Hex(e))?;
+				}
+				Ok(())
+			}
+			PatchError::ProtectionDenied(e) => {
+				write!(f, "cannot make std's code writable: {e}")
+			}
+			PatchError::UnsupportedArch => {
+				f.write_str("only supported on x86_64, x86, aarch64 and riscv64")
+			}
+			PatchError::UnsupportedToolchain { function } => {
+				write!(
+					f,
+					"{function} is not as expected: alternate-flag branch not found"
+				)
+			}
+			PatchError::Unverified {
+				function,
+				reason,
+				listing,
+			} => {
+				write!(f, "{function} is not as expected: {reason}:\n{listing}")
+			}
+			PatchError::UnsupportedRelease { release, .. } => {
+				write!(f, "rustc {release} not yet supported by compact-debug")
+			}
+			PatchError::NotRelocatable { function, found } if found.is_empty() => {
+				write!(f, "{function} cannot be detoured: no room for a jump")
+			}
+			PatchError::NotRelocatable { function, found } => {
+				write!(
+					f,
+					"{function} cannot be detoured: {} cannot be moved",
+					Hex(found)
+				)
+			}
+			PatchError::ThreadsNotStopped(e) => {
+				write!(f, "cannot stop the other threads to attach a detour: {e}")
+			}
+			PatchError::SelfTestFailed { expected, found } => {
+				write!(
+					f,
+					"the patch does not work as expected: formatted {expected:?} as {found:?}"
+				)
+			}
+			PatchError::InvalidEnv { value, reason } => {
+				write!(f, "COMPACT_DEBUG={value:?} is malformed: {reason}")
+			}
+		}
+	}
+}
+
+impl std::error::Error for PatchError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			PatchError::ProtectionDenied(e) | PatchError::ThreadsNotStopped(e) => Some(e),
+			PatchError::UnsupportedRelease { cause, .. } => Some(cause),
+			_ => None,
+		}
+	}
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for (i, b) in self.0.iter().enumerate() {
+			if i != 0 {
+				f.write_str(" ")?;
+			}
+			write!(f, "{b:02X}")?;
+		}
+		Ok(())
+	}
+}
+
+/// Serializes all writes to the code, both to the patch sites and for detours, and keeps track of
+/// the live [`CompactGuard`]s.
+static REFS: Mutex<Refs> = Mutex::new(Refs {
+	count: [0; Targets::COUNT],
+	base: Targets::empty(),
+});
+
+#[derive(Clone)]
+struct Refs {
+	/// The number of live guards for each target, which stays enabled as long as this is nonzero.
+	count: [usize; Targets::COUNT],
+	/// The targets that are enabled regardless of guards.
+	base: Targets,
+}
+
+impl Refs {
+	fn guarded(&self) -> Targets {
+		let mut targets = Targets::empty();
+		for (i, target) in Targets::all().iter() {
+			if self.count[i] != 0 {
+				targets |= target;
+			}
+		}
+		targets
+	}
+}
+
+fn refs() -> MutexGuard<'static, Refs> {
+	REFS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What was enabled before a call to [`update`].
+struct Previous {
+	/// The targets that were enabled regardless of guards.
+	base: Targets,
+	/// The targets that were enabled at all.
+	enabled: Targets,
+}
+
+/// Applies `change` to the bookkeeping, and then brings the code in line with it.
+///
+/// Targets that are not held by any guard are assumed to be in whatever state the code is in, so
+/// that changes made behind our back are not undone. If anything fails, neither the bookkeeping
+/// nor the code is changed.
+unsafe fn update(change: impl FnOnce(&mut Refs)) -> Result<Previous, PatchError> {
+	let mut refs = refs();
+	let mut located = Vec::new();
+	let mut current = Targets::empty();
+	for (_, target) in Targets::all().iter() {
+		let sites = unsafe { Site::locate_all(target) };
+		if let Ok(sites) = &sites {
+			if sites
+				.iter()
+				.any(|s| unsafe { s.state() } == PatchState::Enabled)
+			{
+				current |= target;
+			}
+		}
+		located.push((target, sites, unsafe { Site::locate_hosts(target) }));
+	}
+
+	let guarded = refs.guarded();
+	let previous = Previous {
+		base: current & !guarded | refs.base & guarded,
+		enabled: current | guarded,
+	};
+	let mut new = refs.clone();
+	new.base = previous.base;
+	change(&mut new);
+	let wanted = new.base | new.guarded();
+	#[cfg(target_arch = "x86_64")]
+	if wanted.contains(Targets::TUPLE) {
+		width::install();
+	}
+
+	let mut changes = Vec::new();
+	for (target, sites, hosts) in located {
+		let state = PatchState::from(wanted.contains(target));
+		let sites = match sites {
+			Ok(sites) => sites,
+			// A target that cannot be found cannot have been enabled either, so it only matters if
+			// it is about to be.
+			Err(e) if wanted.contains(target) => return Err(blame_toolchain(e)),
+			Err(_) => continue,
+		};
+		let host_state = match target {
+			Targets::TUPLE if !named_tuples() => PatchState::Disabled,
+			_ => state,
+		};
+		let sites = sites.into_iter().map(|site| (site, state));
+		for (site, state) in sites.chain(hosts.into_iter().map(|site| (site, host_state))) {
+			if unsafe { site.state() } != state {
+				changes.push((site, state));
+			}
+		}
+	}
+	for (i, (site, state)) in changes.iter().enumerate() {
+		if let Err(e) = unsafe { site.write(*state) } {
+			for (site, state) in &changes[..i] {
+				let _ = unsafe { site.write(!*state) };
+			}
+			return Err(e);
+		}
+	}
+	if wanted.contains(Targets::TUPLE) && !changes.is_empty() {
+		if let Err(e) = selftest::run(wanted) {
+			for (site, state) in changes.iter().rev() {
+				let _ = unsafe { site.write(!*state) };
+			}
+			return Err(e);
+		}
+	}
+
+	*refs = new;
+	Ok(previous)
+}
+
+/// Whether the policy has tuples with a name printed on a single line, which only ever does not hold
+/// once `DebugTuple` is detoured, since that is what it takes to tell them apart.
+///
+/// Derived impls of tuple structs and variants go through the hosts of [`Targets::TUPLE`], which
+/// cannot be detoured, so they are left alone while this does not hold.
+fn named_tuples() -> bool {
+	#[cfg(target_arch = "x86_64")]
+	let detoured = width::installed() == Some(true);
+	#[cfg(not(target_arch = "x86_64"))]
+	let detoured = false;
+	!detoured || TUPLE_POLICY.load(Ordering::Relaxed) != TuplePolicy::Anonymous as u8
+}
+
+/// Blames a failure to find what to patch on the toolchain, unless it is one that the patch is known
+/// to work with.
+fn blame_toolchain(e: PatchError) -> PatchError {
+	if supported_toolchains().iter().any(Toolchain::is_current) {
+		return e;
+	}
+	match e {
+		PatchError::UnexpectedBytes { .. }
+		| PatchError::UnsupportedToolchain { .. }
+		| PatchError::Unverified { .. } => PatchError::UnsupportedRelease {
+			release: env!("COMPACT_DEBUG_RUSTC_RELEASE"),
+			cause: Box::new(e),
+		},
+		e => e,
+	}
+}
+
+/// Enables exactly the given targets, and disables all others, returning which were enabled before.
+///
+/// While any guard returned by [`scoped_with`] is alive, its targets stay enabled, and this instead
+/// sets the state they return to once the last of them is dropped.
+///
+/// This may be called from any thread; changes to the patch are serialized, and threads that are
+/// concurrently formatting something see either the old or the new behavior. On x86_64 Linux, the
+/// first time tuples are enabled, or [`set_max_width`] or [`set_tuple_policy`] is called, the other
+/// threads are briefly stopped with `SIGURG` while `DebugTuple` is detoured, and stay stopped for
+/// at most a fraction of a second if some of them block the signal, after which tuples are printed
+/// on a single line no matter how wide.
+///
+/// # Errors
+/// Fails if any of the functions to patch does not look like expected, if their code cannot be
+/// made writable, or if formatting a few values right after patching shows that the patch does not
+/// work, such as when LTO gave the impls of `std` copies of the builders of their own. In any case
+/// the code is left untouched, and `{:#?}` keeps its usual output.
+///
+/// # Safety
+/// This modifies the code of `std` at runtime, which is inherently unsafe. Other code doing the
+/// same, such as another copy of this crate, is not synchronized with.
+pub unsafe fn try_enable_with(targets: Targets) -> Result<Targets, PatchError> {
+	unsafe { update(|refs| refs.base = targets) }.map(|p| p.base)
+}
 
-#[cfg(not(target_arch = "x86_64"))]
-compile_error!("only supported on x86_64");
+/// Enables exactly the given targets, and disables all others.
+///
+/// # Panics
+/// Panics if [`try_enable_with`] fails.
+///
+/// # Safety
+/// See [`try_enable_with`].
+pub unsafe fn enable_with(targets: Targets) {
+	if let Err(e) = unsafe { try_enable_with(targets) } {
+		panic!("{e}")
+	}
+}
 
-const ORIGINAL: [u8; 2] = [0x75, 0x3E]; // jne 0x40
-const PATCHED: [u8; 2] = [0x66, 0x90]; // nop
+/// Enables or disables the patch for [`Targets::TUPLE`], returning whether it was enabled before.
+///
+/// Other targets are left as they are. See [`try_enable_with`] for details.
+///
+/// # Errors
+/// See [`try_enable_with`].
+///
+/// # Safety
+/// See [`try_enable_with`].
+pub unsafe fn try_enable(on: bool) -> Result<PatchState, PatchError> {
+	let previous = unsafe {
+		update(|refs| {
+			refs.base = match on {
+				true => refs.base | Targets::TUPLE,
+				false => refs.base & !Targets::TUPLE,
+			}
+		})
+	}?;
+	Ok(previous.base.contains(Targets::TUPLE).into())
+}
 
-/// Enables or disables the patch.
+/// Enables or disables the patch for [`Targets::TUPLE`].
 ///
 /// # Panics
-/// Panics if the function does not look like expected, which is most likely to happen if `std`
-/// changes something internally, or if the compiler finds a better way to optimize it.
+/// Panics if [`try_enable`] fails.
 ///
 /// # Safety
-/// Aside from the whole concept being inherently unsafe, this will probably have unexpected
-/// consequences if called in multi-threaded contexts.
+/// See [`try_enable`].
 pub unsafe fn enable(on: bool) {
-	unsafe {
-		let function = std::fmt::DebugTuple::field as *const () as *const u8;
-		let ptr = function.offset(0x46) as *mut [u8; 2];
-		if !matches!(*ptr, ORIGINAL | PATCHED) {
-			panic!("DebugTuple::field is not as expected")
+	if let Err(e) = unsafe { try_enable(on) } {
+		panic!("{e}")
+	}
+}
+
+/// Enables the patch as the `COMPACT_DEBUG` environment variable says, returning which targets were
+/// enabled before.
+///
+/// The variable holds a comma-separated list of:
+/// - `off`, which disables all targets,
+/// - `tuples`, `structs`, `lists` and `maps`, which enable [`Targets::TUPLE`], [`Targets::STRUCT`],
+///   [`Targets::LIST`] and [`Targets::MAP`] respectively,
+/// - `all`, which enables all of them,
+/// - `width=N`, which sets the maximum content width, see [`set_max_width`], and is refused where
+///   that has no effect.
+///
+/// The listed targets are enabled and all others disabled, like [`try_enable_with`] does. If none
+/// are listed, such as with only `width=N`, or the variable is unset or empty, this instead enables
+/// [`Targets::TUPLE`] and leaves the others as they are, like `try_enable(true)` does.
+///
+/// # Errors
+/// Fails if the variable is malformed, or sets a width where that has no effect, in which case
+/// nothing is changed, or otherwise as [`try_enable_with`] does.
+///
+/// # Safety
+/// See [`try_enable_with`].
+pub unsafe fn enable_from_env() -> Result<Targets, PatchError> {
+	unsafe { enable_from(std::env::var_os("COMPACT_DEBUG")) }
+}
+
+/// Enables the patch as `value` says, see [`enable_from_env`].
+unsafe fn enable_from(value: Option<std::ffi::OsString>) -> Result<Targets, PatchError> {
+	let config = match value {
+		Some(value) => {
+			let invalid = |reason: String| PatchError::InvalidEnv {
+				value: value.to_string_lossy().into_owned(),
+				reason,
+			};
+			let value = value
+				.to_str()
+				.ok_or_else(|| invalid("it is not valid UTF-8".to_string()))?;
+			let config = match value.trim() {
+				"" => env::Config::default(),
+				value => env::parse(value).map_err(invalid)?,
+			};
+			if config.max_width.is_some() && !unsafe { lay_out_tuples() } {
+				return Err(invalid(
+					"`width=N` has no effect here, where tuples are printed on a single line no \
+					 matter how wide"
+						.to_string(),
+				));
+			}
+			config
+		}
+		None => env::Config::default(),
+	};
+	let previous = match config.targets {
+		Some(targets) => unsafe { update(|refs| refs.base = targets) },
+		None => unsafe { update(|refs| refs.base |= Targets::TUPLE) },
+	}?;
+	if let Some(width) = config.max_width {
+		MAX_WIDTH.store(width, Ordering::Relaxed);
+	}
+	Ok(previous.base)
+}
+
+/// What became of the patch in one of the images loaded in the process, as reported by
+/// [`enable_all_images`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ImageReport {
+	/// The file the image was loaded from.
+	pub path: PathBuf,
+	/// What the image was loaded at, relative to the addresses in the file.
+	pub base: usize,
+	/// Whether the patch was enabled in the image before, or why it could not be changed.
+	pub result: Result<PatchState, PatchError>,
+}
+
+/// Enables or disables the patch for [`Targets::TUPLE`] in every image loaded in the process that
+/// has its own copy of `std`, such as `cdylib` plugins, and reports on each of them.
+///
+/// The image that this crate uses the `std` of comes first, and is changed like [`try_enable`]
+/// does. The others have no bookkeeping of their own, so guards do not keep them enabled, and they
+/// are only changed by calling this again. Images that none of the functions to patch can be found
+/// in are assumed not to contain a copy of `std`, and are left out.
+///
+/// Images are only listed on Linux. Elsewhere, only the first one is reported on.
+///
+/// # Safety
+/// See [`try_enable_with`].
+pub unsafe fn enable_all_images(on: bool) -> Vec<ImageReport> {
+	let functions = Targets::TUPLE.functions();
+	let (own, others): (Vec<_>, Vec<_>) = images::images()
+		.into_iter()
+		.partition(|i| i.contains(functions[0].ptr as usize));
+	let (path, base) = match own.into_iter().next() {
+		Some(image) => (image.path, image.base),
+		None => (std::env::current_exe().unwrap_or_default(), 0),
+	};
+	let mut reports = vec![ImageReport {
+		path,
+		base,
+		result: unsafe { try_enable(on) },
+	}];
+
+	let _refs = refs();
+	let hosts = resolve::hosts(Targets::TUPLE);
+	for image in others {
+		let file = image.read();
+		let symbols = elf::functions(&file);
+		// Functions that an image does not use may well have been left out of it.
+		let found: Vec<_> = functions
+			.iter()
+			.filter_map(|&f| unsafe { image.find(&symbols, f) })
+			.collect();
+		// Hosts are too large to be scanned without knowing how large.
+		let found_hosts: Vec<_> = hosts
+			.iter()
+			.filter_map(|&(host, _)| match unsafe { image.find(&symbols, host) } {
+				Some((host, Some(size))) => Some((host, size)),
+				_ => None,
+			})
+			.collect();
+		if found.is_empty() && found_hosts.is_empty() {
+			continue;
+		}
+		reports.push(ImageReport {
+			path: image.path,
+			base: image.base,
+			result: unsafe { patch_image(&found, &found_hosts, on.into()) },
+		});
+	}
+	reports
+}
+
+/// Brings the sites in an image other than the one this crate uses the `std` of to `state`,
+/// returning what they were in before, given the functions to patch in it along with their sizes,
+/// and the hosts of those like [`Site::locate_all`] takes them.
+unsafe fn patch_image(
+	functions: &[(Function, Option<usize>)],
+	hosts: &[(Function, usize)],
+	state: PatchState,
+) -> Result<PatchState, PatchError> {
+	let mut sites = Vec::new();
+	for &(function, size) in functions {
+		sites.extend(match size {
+			Some(size) => unsafe { Site::locate_sized(function, size) },
+			None => unsafe { Site::locate(function) },
+		}?);
+	}
+	for &(host, size) in hosts {
+		sites.extend(unsafe { Site::locate_sized(host, size) }.unwrap_or_default());
+	}
+	sites.sort_by_key(|s| s.ptr);
+	sites.dedup_by_key(|s| s.ptr);
+	let previous = match sites
+		.iter()
+		.any(|s| unsafe { s.state() } == PatchState::Enabled)
+	{
+		true => PatchState::Enabled,
+		false => PatchState::Disabled,
+	};
+	let changes: Vec<_> = sites
+		.iter()
+		.filter(|s| unsafe { s.state() } != state)
+		.collect();
+	for (i, site) in changes.iter().enumerate() {
+		if let Err(e) = unsafe { site.write(state) } {
+			for site in &changes[..i] {
+				let _ = unsafe { site.write(!state) };
+			}
+			return Err(e);
+		}
+	}
+	Ok(previous)
+}
+
+/// The widest the content of a tuple may be to be printed on a single line, see [`set_max_width`].
+static MAX_WIDTH: AtomicUsize = AtomicUsize::new(100);
+
+/// Sets how many characters wide the content of a tuple may be to still be printed on a single line,
+/// which is 100 by default.
+///
+/// This is a content width rather than a column: only the fields, the commas between them and the
+/// parentheses around them are counted, while the name before the tuple and its indentation are
+/// not, so a nested tuple may still end up past the limit. Wider tuples keep their usual layout.
+/// Fields that span several lines themselves do not count against the limit as a whole, only each
+/// of their lines does.
+///
+/// Derived impls of tuple structs and variants are not held to the limit, since they mostly go
+/// through functions with copies of `DebugTuple` of their own, see [`PatchStatus::unpatched`],
+/// which can only be patched to print on a single line regardless.
+///
+/// Returns whether the width has an effect, which takes detouring `DebugTuple`, and so is only ever
+/// the case on x86_64. Elsewhere, tuples are printed on a single line no matter how wide they are.
+/// The width is kept either way.
+///
+/// # Safety
+/// This detours `DebugTuple` unless that already happened, see [`try_enable_with`].
+pub unsafe fn set_max_width(width: usize) -> bool {
+	MAX_WIDTH.store(width, Ordering::Relaxed);
+	unsafe { lay_out_tuples() }
+}
+
+/// Which tuples to print on a single line, see [`set_tuple_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum TuplePolicy {
+	/// All of them, which is the default.
+	#[default]
+	All,
+	/// Only those with a name, which are tuple structs and tuple variants such as `Address(30016)`,
+	/// leaving tuples such as `(1, 2)` in their usual layout.
+	Named,
+	/// Only those without a name, which are tuples such as `(1, 2)`, leaving tuple structs and
+	/// tuple variants in their usual layout.
+	Anonymous,
+}
+
+/// Which tuples to print on a single line, see [`set_tuple_policy`], as a [`TuplePolicy`] cast to
+/// `u8`.
+static TUPLE_POLICY: AtomicU8 = AtomicU8::new(TuplePolicy::All as u8);
+
+/// Sets which tuples are printed on a single line, telling them apart by whether they have a name,
+/// which is all of them by default.
+///
+/// Those that the policy leaves out keep their usual layout, while the others are still subject to
+/// [`set_max_width`]. Derived impls of tuple structs and variants follow the policy by whether the
+/// functions they go through are patched at all, which this changes right away.
+///
+/// Returns whether the policy has an effect, which, like with [`set_max_width`], is only ever the
+/// case on x86_64. Elsewhere, all tuples are printed on a single line. The policy is kept either
+/// way.
+///
+/// # Safety
+/// This patches the functions that derived impls go through, and detours `DebugTuple` unless that
+/// already happened, see [`try_enable_with`].
+pub unsafe fn set_tuple_policy(policy: TuplePolicy) -> bool {
+	TUPLE_POLICY.store(policy as u8, Ordering::Relaxed);
+	// The hosts of `DebugTuple` follow the policy by being patched or not.
+	unsafe { update(|_| {}) }.is_ok() && unsafe { lay_out_tuples() }
+}
+
+/// Detours `DebugTuple` unless that already happened, returning whether tuples can be laid out by
+/// their width and the policy.
+///
+/// The detours are written while holding [`REFS`], like all other changes to the code.
+unsafe fn lay_out_tuples() -> bool {
+	let _refs = refs();
+	#[cfg(target_arch = "x86_64")]
+	return width::install();
+	#[cfg(not(target_arch = "x86_64"))]
+	return false;
+}
+
+impl Toolchain {
+	/// Whether this is the toolchain that this crate was built with, for the same target.
+	pub fn is_current(&self) -> bool {
+		self.commit == env!("COMPACT_DEBUG_RUSTC_COMMIT")
+			&& self.target == env!("COMPACT_DEBUG_TARGET")
+	}
+}
+
+/// The toolchains that the patch is known to work with, oldest first.
+///
+/// Other toolchains are not turned away, since `std` rarely changes in a way that matters, but if
+/// the patch fails on one of them, the error says that it is not supported yet. To check ahead of
+/// time, for example in CI, look for one where [`Toolchain::is_current`] holds.
+pub fn supported_toolchains() -> &'static [Toolchain] {
+	toolchains::TOOLCHAINS
+}
+
+/// What the patch site currently holds, as reported by [`status`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SiteStatus {
+	/// `DebugTuple` is printed on a single line.
+	Enabled,
+	/// `DebugTuple` is printed the way `std` intends.
+	Disabled,
+	/// The alternate-flag test was found, but is followed by these unrecognized bytes.
+	Unrecognized(Vec<u8>),
+	/// The patch site could not be found at all, or this architecture is not supported.
+	Unsupported,
+}
+
+/// A report on the patch, returned by [`status`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct PatchStatus {
+	/// What the patch site currently holds.
+	pub status: SiteStatus,
+	/// The address of the patched instruction in `DebugTuple::field`, if it was found.
+	pub address: Option<usize>,
+	/// The version of `rustc`, and thereby `std`, that this crate was built with.
+	pub std_version: &'static str,
+	/// The functions that derived impls go through which still print tuples over several lines
+	/// while the patch is enabled, such as `Formatter::debug_tuple_field2_finish`.
+	///
+	/// These have copies of `DebugTuple::field` of their own, which can only be patched if they
+	/// are in the symbol table. This is always empty while the patch is disabled. The impls of
+	/// `std` itself are not listed, since the patch cannot be enabled while those bypass it.
+	pub unpatched: Vec<&'static str>,
+}
+
+impl PatchStatus {
+	/// Whether the patch is currently applied.
+	pub fn is_enabled(&self) -> bool {
+		self.status == SiteStatus::Enabled
+	}
+}
+
+/// Reads the patch site in `DebugTuple::field` and reports what it holds, without modifying
+/// anything.
+///
+/// While the patch is enabled, this also formats a few values with derived impls to find out which
+/// of the functions that those go through are left unpatched.
+pub fn status() -> PatchStatus {
+	let (status, address) = site_status();
+	let unpatched = match status {
+		SiteStatus::Enabled => selftest::unpatched(),
+		_ => Vec::new(),
+	};
+	PatchStatus {
+		status,
+		address,
+		std_version: env!("COMPACT_DEBUG_RUSTC_VERSION"),
+		unpatched,
+	}
+}
+
+/// Reads the patch site in `DebugTuple::field`, returning what it holds and where it is.
+fn site_status() -> (SiteStatus, Option<usize>) {
+	// SAFETY: this only reads the code of `DebugTuple::field`, which is always readable.
+	let sites = unsafe { Site::locate(Targets::TUPLE.functions()[0]) };
+	match sites {
+		Ok(sites) => {
+			let site = &sites[0];
+			let status = match unsafe { site.state() } {
+				PatchState::Enabled => SiteStatus::Enabled,
+				PatchState::Disabled => SiteStatus::Disabled,
+			};
+			(status, Some(site.ptr as usize))
 		}
-		let _prot =
-			region::protect_with_handle(ptr, 2, region::Protection::READ_WRITE_EXECUTE).unwrap();
-		ptr.write(if on { PATCHED } else { ORIGINAL });
+		Err(PatchError::UnexpectedBytes { found, .. }) => (SiteStatus::Unrecognized(found), None),
+		Err(_) => (SiteStatus::Unsupported, None),
+	}
+}
+
+/// Whether the patch for [`Targets::TUPLE`] is currently applied.
+///
+/// Shorthand for `status().is_enabled()`, without checking derived impls.
+pub fn is_enabled() -> bool {
+	site_status().0 == SiteStatus::Enabled
+}
+
+/// Keeps the patch enabled while alive.
+///
+/// Returned by [`scoped`] and [`scoped_with`]. Once the last live guard for a target is dropped,
+/// that target is restored to the state it was in before the first of them was created.
+#[must_use]
+pub struct CompactGuard {
+	targets: Targets,
+	previous: PatchState,
+}
+
+impl CompactGuard {
+	/// Whether all of this guard's targets were already enabled when it was created.
+	pub fn previous(&self) -> PatchState {
+		self.previous
+	}
+}
+
+impl Drop for CompactGuard {
+	fn drop(&mut self) {
+		// This already succeeded when the guard was created, so there is no reason for it to fail
+		// now, and nowhere to report it if it did.
+		let _ = unsafe {
+			update(|refs| {
+				for (i, _) in self.targets.iter() {
+					refs.count[i] -= 1;
+				}
+			})
+		};
+	}
+}
+
+impl fmt::Debug for CompactGuard {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("CompactGuard")
+			.field("targets", &self.targets)
+			.field("previous", &self.previous)
+			.finish()
 	}
 }
 
+/// Enables the given targets until the returned guard is dropped, after which they are restored to
+/// whatever state they were in before.
+///
+/// Unlike [`enable_with`], this is safe to use in helpers that do not know whether the patch is
+/// already enabled. Guards are reference counted, so they nest as expected and may be held by
+/// several threads at once; each target is only reverted when the last guard holding it is dropped.
+///
+/// # Errors
+/// See [`try_enable_with`].
+///
+/// # Safety
+/// See [`try_enable_with`].
+pub unsafe fn scoped_with(targets: Targets) -> Result<CompactGuard, PatchError> {
+	let previous = unsafe {
+		update(|refs| {
+			for (i, _) in targets.iter() {
+				refs.count[i] += 1;
+			}
+		})
+	}?;
+	Ok(CompactGuard {
+		targets,
+		previous: previous.enabled.contains(targets).into(),
+	})
+}
+
+/// Enables the patch for [`Targets::TUPLE`] until the returned guard is dropped.
+///
+/// See [`scoped_with`] for details.
+///
+/// # Errors
+/// See [`try_enable_with`].
+///
+/// # Safety
+/// See [`try_enable_with`].
+pub unsafe fn scoped() -> Result<CompactGuard, PatchError> {
+	unsafe { scoped_with(Targets::TUPLE) }
+}
+
+#[cfg(test)]
+static TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Whether the patch can be enabled in this build of the tests, which it cannot with LTO, where the
+/// impls of `std` have copies of the builders of their own, which the self-test catches.
+///
+/// Tests that enable the patch have nothing to check otherwise, and `test_selftest` checks that it
+/// fails as it should.
+#[cfg(test)]
+fn patchable() -> bool {
+	static PATCHABLE: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+	*PATCHABLE.get_or_init(|| match unsafe { try_enable(true) } {
+		Ok(previous) => {
+			unsafe { enable(previous == PatchState::Enabled) };
+			true
+		}
+		Err(PatchError::SelfTestFailed { .. }) => false,
+		Err(e) => panic!("{e}"),
+	})
+}
+
 #[test]
 fn test() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	#[allow(dead_code)]
 	#[derive(Debug)]
 	struct A(u32, u32);
 
@@ -117,3 +1288,1239 @@ fn test() {
 	assert_eq!(format!("{b:?}"), "B { x: 8, y: 32 }");
 	assert_eq!(format!("{b:#?}"), "B {\n    x: 8,\n    y: 32,\n}");
 }
+
+#[test]
+fn test_scoped() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	let t = (8, 32);
+	let pretty = "(\n    8,\n    32,\n)";
+
+	assert!(!is_enabled());
+	assert_eq!(format!("{t:#?}"), pretty);
+	{
+		let outer = unsafe { scoped() }.unwrap();
+		assert_eq!(outer.previous(), PatchState::Disabled);
+		assert_eq!(format!("{t:#?}"), "(8, 32)");
+		{
+			let inner = unsafe { scoped() }.unwrap();
+			assert_eq!(inner.previous(), PatchState::Enabled);
+			assert!(is_enabled());
+			assert_eq!(format!("{t:#?}"), "(8, 32)");
+		}
+		assert_eq!(format!("{t:#?}"), "(8, 32)");
+	}
+	assert_eq!(format!("{t:#?}"), pretty);
+
+	let result = std::panic::catch_unwind(|| {
+		let _guard = unsafe { scoped() }.unwrap();
+		panic!("unwinding");
+	});
+	assert!(result.is_err());
+	assert_eq!(format!("{t:#?}"), pretty);
+}
+
+#[test]
+fn test_status() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	let before = status();
+	assert_eq!(before.status, SiteStatus::Disabled);
+	assert!(before.address.is_some());
+	assert!(before.std_version.starts_with("rustc "));
+	assert!(before.unpatched.is_empty());
+
+	// Derived impls are patched through the symbol table, unless LTO inlined the functions they go
+	// through into them, which depends on the build.
+	let guard = unsafe { scoped() }.unwrap();
+	let during = status();
+	assert_eq!(during.status, SiteStatus::Enabled);
+	assert_eq!(during.address, before.address);
+	let hosts = Targets::TUPLE.hosts();
+	assert!(
+		during.unpatched.iter().all(|host| hosts.contains(host)),
+		"{:?}",
+		during.unpatched
+	);
+	drop(guard);
+
+	assert_eq!(status(), before);
+}
+
+#[test]
+fn test_tuple_shapes() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	struct Tuple(&'static str, usize, bool);
+
+	impl std::fmt::Debug for Tuple {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			let mut tuple = f.debug_tuple(self.0);
+			for i in 0..self.1 {
+				tuple.field(&i);
+			}
+			match self.2 {
+				true => tuple.finish_non_exhaustive(),
+				false => tuple.finish(),
+			}
+		}
+	}
+
+	#[derive(Debug)]
+	struct Unit;
+	#[derive(Debug)]
+	struct Empty();
+	#[allow(dead_code)]
+	#[derive(Debug)]
+	enum E {
+		Unit,
+		Empty(),
+		One(u8),
+		Two(u8, u8),
+	}
+
+	let pretty: Vec<_> = (0..8)
+		.flat_map(|n| [("", n), ("T", n)])
+		.flat_map(|(name, n)| [Tuple(name, n, false), Tuple(name, n, true)])
+		.map(|t| (format!("{t:#?}"), t))
+		.collect();
+
+	let guard = unsafe { scoped() }.unwrap();
+
+	assert_eq!(format!("{:#?}", (5,)), "(5,)");
+	assert_eq!(format!("{:#?}", ((5,),)), "((5,),)");
+	assert_eq!(format!("{:#?}", Some((5,))), "Some((5,))");
+	assert_eq!(format!("{:#?}", ()), "()");
+	assert_eq!(format!("{:#?}", ((),)), "((),)");
+	assert_eq!(format!("{:#?}", Unit), "Unit");
+	assert_eq!(format!("{:#?}", Empty()), "Empty");
+	assert_eq!(
+		format!("{:#?}", [E::Unit, E::Empty()]),
+		"[\n    Unit,\n    Empty,\n]"
+	);
+	assert_eq!(
+		format!("{:#?}", (E::One(1), E::Two(1, 2))),
+		"(One(1), Two(1, 2))"
+	);
+	assert_eq!(
+		format!("{:#?}", (1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12)),
+		"(1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12)"
+	);
+	// Every number of fields, with and without a name, comes out as it does without `#`.
+	for (_, t) in &pretty {
+		assert_eq!(format!("{t:#?}"), format!("{t:?}"));
+	}
+
+	drop(guard);
+	for (pretty, t) in &pretty {
+		assert_eq!(&format!("{t:#?}"), pretty);
+	}
+	assert_eq!(format!("{:#?}", (5,)), "(\n    5,\n)");
+}
+
+#[test]
+fn test_build_sites() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let field = Targets::TUPLE.functions()[0];
+	let sites = unsafe { Site::locate(field) }.unwrap();
+	let offsets: Vec<_> = sites
+		.iter()
+		.map(|s| s.ptr as usize - field.ptr as usize)
+		.collect();
+	assert_eq!(offsets, FIELD_OFFSETS);
+}
+
+#[test]
+fn test_toolchains() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let e = PatchError::UnsupportedRelease {
+		release: "1.0.0",
+		cause: Box::new(PatchError::UnsupportedToolchain {
+			function: "DebugTuple::field",
+		}),
+	};
+	assert_eq!(
+		e.to_string(),
+		"rustc 1.0.0 not yet supported by compact-debug"
+	);
+
+	let Some(t) = supported_toolchains().iter().find(|t| t.is_current()) else {
+		return;
+	};
+	assert_eq!(FIELD_OFFSETS.first(), Some(&t.offset));
+	assert_eq!(FIELD_ORIGINAL[0], t.original);
+	let field = Targets::TUPLE.functions()[0];
+	let sites = unsafe { Site::locate(field) }.unwrap();
+	let site = sites
+		.iter()
+		.find(|s| s.ptr as usize == field.ptr as usize + t.offset)
+		.unwrap();
+	assert_eq!(site.original.to_le_bytes(), t.original);
+	assert_eq!(site.patched.to_le_bytes(), t.patched);
+}
+
+#[test]
+fn test_selftest() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let e = PatchError::SelfTestFailed {
+		expected: "(5,)",
+		found: "(5)".to_string(),
+	};
+	assert_eq!(
+		e.to_string(),
+		"the patch does not work as expected: formatted \"(5,)\" as \"(5)\""
+	);
+
+	// With LTO, the impls of `std` bypass the patch, and the code is left as it was.
+	if !patchable() {
+		let e = unsafe { try_enable(true) }.unwrap_err();
+		assert!(matches!(e, PatchError::SelfTestFailed { .. }), "{e}");
+		assert!(!is_enabled());
+		assert_eq!(format!("{:#?}", (5,)), "(\n    5,\n)");
+		return;
+	}
+
+	// The probes ignore the width limit, and match whichever targets are enabled.
+	unsafe { set_max_width(0) };
+	unsafe { enable(true) };
+	unsafe { set_max_width(100) };
+	selftest::run(Targets::TUPLE).unwrap();
+	assert_eq!(format!("{:#?}", (5,)), "(5,)");
+	assert_eq!(format!("{:#?}", ((5,), 6)), "((5,), 6)");
+	assert!(selftest::run(Targets::TUPLE | Targets::LIST).is_err());
+
+	unsafe { enable_with(Targets::TUPLE | Targets::LIST) };
+	selftest::run(Targets::TUPLE | Targets::LIST).unwrap();
+
+	unsafe { enable_with(Targets::empty()) };
+	assert_eq!(format!("{:#?}", (5,)), "(\n    5,\n)");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_images() {
+	use std::os::unix::ffi::OsStrExt;
+
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	const PLUGIN: &str = "
+		#[derive(Debug)]
+		#[allow(dead_code)]
+		struct Address(u32);
+
+		#[no_mangle]
+		pub extern \"C\" fn compact_debug_plugin(out: *mut u8, len: usize) -> usize {
+			let s = format!(\"{:#?}\", (Address(30016), (8, 32), (5,)));
+			let n = s.len().min(len);
+			unsafe { std::ptr::copy_nonoverlapping(s.as_ptr(), out, n) };
+			n
+		}
+	";
+	type Plugin = extern "C" fn(*mut u8, usize) -> usize;
+	#[derive(Debug)]
+	#[allow(dead_code)]
+	struct Address(u32);
+	let pretty = format!("{:#?}", (Address(30016), (8, 32), (5,)));
+
+	// Both with a symbol table and without, which has to be searched instead.
+	let dir = PathBuf::from(env!("OUT_DIR"));
+	std::fs::write(dir.join("plugin.rs"), PLUGIN).unwrap();
+	let mut plugins = Vec::new();
+	let rustc = std::env::var_os("RUSTC").unwrap_or("rustc".into());
+	for strip in ["none", "symbols"] {
+		let path = dir.join(format!("libcompact_debug_plugin_{strip}.so"));
+		let status = std::process::Command::new(&rustc)
+			.args(["--crate-type=cdylib", "--edition=2021", "-C"])
+			.arg(format!("strip={strip}"))
+			.arg("-o")
+			.arg(&path)
+			.arg(dir.join("plugin.rs"))
+			.status()
+			.unwrap();
+		assert!(status.success());
+		let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+		let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+		assert!(!handle.is_null());
+		let plugin = unsafe { libc::dlsym(handle, c"compact_debug_plugin".as_ptr()) };
+		assert!(!plugin.is_null());
+		plugins.push((path, unsafe {
+			std::mem::transmute::<*mut libc::c_void, Plugin>(plugin)
+		}));
+	}
+	let run = |plugin: Plugin| {
+		let mut buf = [0; 256];
+		let n = plugin(buf.as_mut_ptr(), buf.len());
+		String::from_utf8(buf[..n].to_vec()).unwrap()
+	};
+
+	for &(_, plugin) in &plugins {
+		assert_eq!(run(plugin), pretty);
+	}
+
+	let reports = unsafe { enable_all_images(true) };
+	assert_eq!(reports.len(), 3, "{reports:#?}");
+	assert_eq!(reports[0].result.as_ref().ok(), Some(&PatchState::Disabled));
+	assert!(is_enabled());
+	for (path, plugin) in &plugins {
+		let report = reports.iter().find(|r| r.path == *path).unwrap();
+		assert_eq!(
+			report.result.as_ref().ok(),
+			Some(&PatchState::Disabled),
+			"{report:#?}"
+		);
+		assert_eq!(run(*plugin), "(Address(30016), (8, 32), (5,))");
+	}
+
+	let reports = unsafe { enable_all_images(false) };
+	assert!(reports.iter().all(|r| r.result.is_ok()));
+	assert!(!is_enabled());
+	for &(_, plugin) in &plugins {
+		assert_eq!(run(plugin), pretty);
+	}
+
+	// A file that is replaced while loaded is not read in place of what was loaded from it.
+	let (path, _) = &plugins[0];
+	let replacement = dir.join("replacement.so");
+	std::fs::write(
+		dir.join("replacement.rs"),
+		format!("{PLUGIN}\n#[no_mangle]\npub extern \"C\" fn compact_debug_replaced() {{}}\n"),
+	)
+	.unwrap();
+	let status = std::process::Command::new(&rustc)
+		.args(["--crate-type=cdylib", "--edition=2021", "-o"])
+		.arg(&replacement)
+		.arg(dir.join("replacement.rs"))
+		.status()
+		.unwrap();
+	assert!(status.success());
+	std::fs::rename(&replacement, path).unwrap();
+	let images = images::images();
+	assert!(!images[0].read().is_empty());
+	let image = images.iter().find(|i| i.path == *path).unwrap();
+	assert!(image.read().is_empty());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_definitions() {
+	let legacy = "_ZN4core3fmt8builders10DebugTuple5field17h0123456789abcdefE";
+	let v0 = "_RNvMNtNtCs1a2b3c_4core3fmt8buildersNtB2_10DebugTuple5field";
+	assert_eq!(
+		demangle::demangle(legacy).as_deref(),
+		Some("core::fmt::builders::DebugTuple::field")
+	);
+	assert_eq!(
+		demangle::demangle(v0).as_deref(),
+		Some("<core::fmt::builders::DebugTuple>::field")
+	);
+	assert_eq!(
+		demangle::demangle("_RNvCs1a2b3c_7mycrate3foo.llvm.123").as_deref(),
+		Some("mycrate::foo")
+	);
+	assert_eq!(demangle::demangle("_ZN3foo3barEv"), None);
+	assert_eq!(demangle::demangle("main"), None);
+
+	// Under either spelling of the path.
+	let field = Targets::TUPLE.functions()[0];
+	for path in [
+		"<core::fmt::builders::DebugTuple>::field",
+		"core::fmt::builders::DebugTuple::field",
+	] {
+		let found = definitions(path);
+		assert!(
+			found.iter().any(|d| d.address == field.ptr as usize),
+			"{found:#x?}"
+		);
+		assert!(found.iter().all(|d| demangle::same_path(&d.name, path)));
+	}
+	assert!(definitions("core::fmt::builders::DebugTuple::nonexistent").is_empty());
+}
+
+#[test]
+fn test_threads() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	let t = (8, 32);
+	let barrier = std::sync::Barrier::new(8);
+	std::thread::scope(|s| {
+		for _ in 0..8 {
+			s.spawn(|| {
+				let guard = unsafe { scoped() }.unwrap();
+				barrier.wait();
+				for _ in 0..100 {
+					assert_eq!(format!("{t:#?}"), "(8, 32)");
+				}
+				barrier.wait();
+				drop(guard);
+			});
+		}
+	});
+	assert!(!is_enabled());
+	assert_eq!(format!("{t:#?}"), "(\n    8,\n    32,\n)");
+
+	let guard = unsafe { scoped() }.unwrap();
+	unsafe { enable(false) };
+	assert!(is_enabled());
+	unsafe { enable(true) };
+	drop(guard);
+	assert!(is_enabled());
+	unsafe { enable(false) };
+	assert!(!is_enabled());
+}
+
+#[test]
+fn test_struct() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	// Hand-written impls go through `DebugStruct` itself, and derived ones through the hosts of
+	// `Targets::STRUCT`, which have copies of it inlined.
+	struct B {
+		x: u32,
+		y: u32,
+	}
+
+	impl std::fmt::Debug for B {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			f.debug_struct("B")
+				.field("x", &self.x)
+				.field("y", &self.y)
+				.finish()
+		}
+	}
+
+	struct C(Vec<u32>);
+
+	impl std::fmt::Debug for C {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			f.debug_struct("C").field("v", &self.0).finish()
+		}
+	}
+
+	struct D(u32);
+
+	impl std::fmt::Debug for D {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			f.debug_struct("D")
+				.field("x", &self.0)
+				.finish_non_exhaustive()
+		}
+	}
+
+	#[allow(dead_code)]
+	#[derive(Debug)]
+	struct E {
+		x: u32,
+		y: u32,
+	}
+
+	#[allow(dead_code)]
+	#[derive(Debug)]
+	struct F {
+		x: u32,
+	}
+
+	let b = B { x: 8, y: 32 };
+	let c = C(vec![8, 32]);
+	let d = D(8);
+	let e = E { x: 8, y: 32 };
+	let f = F { x: 8 };
+	let t = (8, 32);
+
+	assert_eq!(format!("{b:#?}"), "B {\n    x: 8,\n    y: 32,\n}");
+	assert_eq!(
+		format!("{c:#?}"),
+		"C {\n    v: [\n        8,\n        32,\n    ],\n}"
+	);
+
+	unsafe { enable_with(Targets::TUPLE | Targets::STRUCT) };
+
+	assert_eq!(format!("{b:?}"), "B { x: 8, y: 32 }");
+	assert_eq!(format!("{b:#?}"), "B { x: 8, y: 32 }");
+	assert_eq!(format!("{c:#?}"), "C { v: [\n    8,\n    32,\n] }");
+	assert_eq!(format!("{d:#?}"), "D { x: 8, .. }");
+	assert_eq!(format!("{e:#?}"), "E { x: 8, y: 32 }");
+	assert_eq!(format!("{f:#?}"), "F { x: 8 }");
+	assert_eq!(
+		format!("{:#?}", std::sync::Mutex::new(5)),
+		format!("{:?}", std::sync::Mutex::new(5))
+	);
+	assert_eq!(format!("{t:#?}"), "(8, 32)");
+
+	unsafe { enable_with(Targets::STRUCT) };
+
+	assert_eq!(format!("{b:#?}"), "B { x: 8, y: 32 }");
+	assert_eq!(format!("{t:#?}"), "(\n    8,\n    32,\n)");
+
+	{
+		let _guard = unsafe { scoped_with(Targets::TUPLE) }.unwrap();
+		assert_eq!(format!("{t:#?}"), "(8, 32)");
+		unsafe { enable(false) };
+		assert_eq!(format!("{b:#?}"), "B { x: 8, y: 32 }");
+	}
+
+	assert_eq!(format!("{t:#?}"), "(\n    8,\n    32,\n)");
+
+	unsafe { enable_with(Targets::empty()) };
+
+	assert_eq!(format!("{b:?}"), "B { x: 8, y: 32 }");
+	assert_eq!(format!("{b:#?}"), "B {\n    x: 8,\n    y: 32,\n}");
+	assert_eq!(format!("{d:#?}"), "D {\n    x: 8,\n    ..\n}");
+	assert_eq!(format!("{e:#?}"), "E {\n    x: 8,\n    y: 32,\n}");
+}
+
+#[test]
+fn test_collections() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	struct Partial(u8);
+
+	impl std::fmt::Debug for Partial {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			match self.0 {
+				0 => f.debug_list().entries([1, 2]).finish_non_exhaustive(),
+				1 => f.debug_set().entries([1, 2]).finish_non_exhaustive(),
+				_ => f
+					.debug_map()
+					.entries([(1, 2), (3, 4)])
+					.finish_non_exhaustive(),
+			}
+		}
+	}
+
+	let v = vec![8, 32];
+	let s = std::collections::BTreeSet::from([8, 32]);
+	let m = std::collections::BTreeMap::from([(1, vec![2]), (3, vec![4])]);
+	let partial = [Partial(0), Partial(1), Partial(2)];
+	let partial_pretty = [
+		"[\n    1,\n    2,\n    ..\n]",
+		"{\n    1,\n    2,\n    ..\n}",
+		"{\n    1: 2,\n    3: 4,\n    ..\n}",
+	];
+	let partial_compact = ["[1, 2, ..]", "{1, 2, ..}", "{1: 2, 3: 4, ..}"];
+	for (p, pretty) in partial.iter().zip(partial_pretty) {
+		assert_eq!(format!("{p:#?}"), pretty);
+	}
+
+	let v_pretty = "[\n    8,\n    32,\n]";
+	let s_pretty = "{\n    8,\n    32,\n}";
+	let m_pretty = "{\n    1: [\n        2,\n    ],\n    3: [\n        4,\n    ],\n}";
+	assert_eq!(format!("{v:#?}"), v_pretty);
+	assert_eq!(format!("{s:#?}"), s_pretty);
+	assert_eq!(format!("{m:#?}"), m_pretty);
+
+	unsafe { enable_with(Targets::LIST) };
+
+	assert_eq!(format!("{v:#?}"), "[8, 32]");
+	assert_eq!(format!("{s:#?}"), "{8, 32}");
+	assert_eq!(format!("{m:#?}"), "{\n    1: [2],\n    3: [4],\n}");
+	assert_eq!(format!("{:#?}", partial[0]), partial_compact[0]);
+	assert_eq!(format!("{:#?}", partial[1]), partial_compact[1]);
+	assert_eq!(format!("{:#?}", partial[2]), partial_pretty[2]);
+
+	unsafe { enable_with(Targets::MAP) };
+
+	assert_eq!(format!("{v:#?}"), v_pretty);
+	assert_eq!(format!("{m:?}"), "{1: [2], 3: [4]}");
+	assert_eq!(format!("{m:#?}"), "{1: [\n    2,\n], 3: [\n    4,\n]}");
+	assert_eq!(format!("{:#?}", partial[0]), partial_pretty[0]);
+	assert_eq!(format!("{:#?}", partial[2]), partial_compact[2]);
+
+	{
+		let _guard = unsafe { scoped_with(Targets::LIST) }.unwrap();
+		assert_eq!(format!("{m:#?}"), "{1: [2], 3: [4]}");
+	}
+
+	assert_eq!(format!("{s:#?}"), s_pretty);
+
+	unsafe { enable_with(Targets::empty()) };
+
+	assert_eq!(format!("{v:#?}"), v_pretty);
+	assert_eq!(format!("{m:#?}"), m_pretty);
+	for (p, pretty) in partial.iter().zip(partial_pretty) {
+		assert_eq!(format!("{p:#?}"), pretty);
+	}
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_width() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	struct N(u32);
+
+	impl std::fmt::Debug for N {
+		fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+			f.debug_tuple("N").field(&self.0).finish_non_exhaustive()
+		}
+	}
+
+	let short = ("a", 1);
+	let long = ("a".repeat(20), "b".repeat(20));
+	let nested = (1, (2, "x".repeat(20)));
+	let pretty = [format!("{long:#?}"), format!("{nested:#?}")];
+
+	unsafe { enable(true) };
+
+	assert_eq!(format!("{short:#?}"), "(\"a\", 1)");
+	assert_eq!(format!("{long:#?}"), format!("{long:?}"));
+	assert_eq!(format!("{nested:#?}"), format!("{nested:?}"));
+	assert_eq!(format!("{:#x?}", (255, 16)), "(0xff, 0x10)");
+	assert_eq!(format!("{:#?}", N(1)), "N(1, ..)");
+
+	assert!(unsafe { set_max_width(20) });
+
+	assert_eq!(format!("{short:#?}"), "(\"a\", 1)");
+	assert_eq!(format!("{long:#?}"), pretty[0]);
+	assert_eq!(format!("{nested:#?}"), pretty[1]);
+	assert_eq!(format!("{:#?}", [(1, 2)]), "[\n    (1, 2),\n]");
+	assert_eq!(format!("{:#?}", (1, vec![2])), "(1, [\n    2,\n])");
+
+	unsafe { set_max_width(5) };
+
+	assert_eq!(format!("{:#?}", N(1)), "N(\n    1,\n    ..\n)");
+
+	unsafe { set_max_width(100) };
+	unsafe { enable(false) };
+
+	assert_eq!(format!("{short:#?}"), "(\n    \"a\",\n    1,\n)");
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+std::arch::global_asm!(
+	".pushsection .text",
+	// A prologue that can be moved.
+	".balign 16",
+	".globl compact_debug_test_add",
+	"compact_debug_test_add:",
+	"push rbp",
+	"mov rbp, rsp",
+	"lea rax, [rdi + rsi]",
+	"pop rbp",
+	"ret",
+	// A RIP-relative operand in the prologue.
+	".balign 16",
+	".globl compact_debug_test_rip",
+	"compact_debug_test_rip:",
+	"lea rax, [rip + compact_debug_test_add]",
+	"ret",
+	// A branch back into the prologue.
+	".balign 16",
+	".globl compact_debug_test_loop",
+	"compact_debug_test_loop:",
+	"mov rax, rdi",
+	"2:",
+	"sub rax, 1",
+	"jnz 2b",
+	"ret",
+	// What looks like a test of the alternate flag, inside a `mov`.
+	".balign 16",
+	".globl compact_debug_test_hidden",
+	"compact_debug_test_hidden:",
+	"movabs rax, 0x90900075801247F6",
+	"ret",
+	"int3",
+	// A test of some other flag.
+	".balign 16",
+	".globl compact_debug_test_other",
+	"compact_debug_test_other:",
+	"test byte ptr [rdi + 0x30], 0x80",
+	"jne 2f",
+	"xor eax, eax",
+	"2:",
+	"ret",
+	"int3",
+	// A test of the alternate flag of something that is not a formatter.
+	".balign 16",
+	".globl compact_debug_test_computed",
+	"compact_debug_test_computed:",
+	"lea rax, [rdi + 8]",
+	"test byte ptr [rax + 0x12], 0x80",
+	"jne 2f",
+	"xor eax, eax",
+	"2:",
+	"ret",
+	"int3",
+	// A branch on the alternate flag that goes on the same way either way.
+	".balign 16",
+	".globl compact_debug_test_same",
+	"compact_debug_test_same:",
+	"mov rax, qword ptr [rdi]",
+	"test byte ptr [rax + 0x12], 0x80",
+	"jne 2f",
+	"2:",
+	"ret",
+	"int3",
+	// `read`, as a system call in the middle of the prologue.
+	".balign 16",
+	".globl compact_debug_test_read",
+	"compact_debug_test_read:",
+	"xor eax, eax",
+	"syscall",
+	"nop",
+	"ret",
+	// A PLT entry, both bound and not yet bound.
+	".balign 16",
+	".globl compact_debug_test_stub",
+	"compact_debug_test_stub:",
+	"endbr64",
+	".byte 0xF2", // bnd
+	"jmp qword ptr [rip + compact_debug_test_slot]",
+	".balign 16",
+	".globl compact_debug_test_lazy",
+	"compact_debug_test_lazy:",
+	"jmp qword ptr [rip + compact_debug_test_lazy_slot]",
+	"compact_debug_test_lazy_next:",
+	"push 0",
+	"int3",
+	".popsection",
+	".pushsection .data",
+	".balign 8",
+	"compact_debug_test_slot:",
+	".quad compact_debug_test_add",
+	"compact_debug_test_lazy_slot:",
+	".quad compact_debug_test_lazy_next",
+	".popsection",
+);
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn test_detour() {
+	use detour::Detour;
+
+	extern "C" {
+		fn compact_debug_test_add(a: u64, b: u64) -> u64;
+		fn compact_debug_test_rip() -> u64;
+		fn compact_debug_test_loop(n: u64) -> u64;
+	}
+
+	static ORIGINAL: std::sync::OnceLock<extern "C" fn(u64, u64) -> u64> =
+		std::sync::OnceLock::new();
+
+	extern "C" fn hook(a: u64, b: u64) -> u64 {
+		ORIGINAL.get().unwrap()(a, b) * 10
+	}
+
+	let add = Function::new(compact_debug_test_add as *const (), "add");
+	let detour = unsafe { Detour::new(add, hook as *const ()) }.unwrap();
+	ORIGINAL.set(unsafe { detour.original() }).unwrap();
+
+	// The trampoline is executable, but no longer writable.
+	let trampoline = unsafe { detour.original::<*const u8>() } as usize;
+	let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+	let line = maps
+		.lines()
+		.find(|l| {
+			let (start, end) = l.split_once(' ').unwrap().0.split_once('-').unwrap();
+			let range =
+				usize::from_str_radix(start, 16).unwrap()..usize::from_str_radix(end, 16).unwrap();
+			range.contains(&trampoline)
+		})
+		.unwrap();
+	assert!(line.split(' ').nth(1).unwrap().starts_with("r-x"), "{line}");
+
+	assert_eq!(unsafe { compact_debug_test_add(2, 3) }, 5);
+	unsafe { detour.attach() }.unwrap();
+	assert_eq!(unsafe { compact_debug_test_add(2, 3) }, 50);
+
+	let rip = Function::new(compact_debug_test_rip as *const (), "rip");
+	let e = unsafe { Detour::new(rip, hook as *const ()) }
+		.err()
+		.unwrap();
+	assert!(
+		matches!(e, PatchError::NotRelocatable { found, .. } if found.starts_with(&[0x48, 0x8D, 0x05]))
+	);
+
+	let looping = Function::new(compact_debug_test_loop as *const (), "loop");
+	let e = unsafe { Detour::new(looping, hook as *const ()) }
+		.err()
+		.unwrap();
+	assert_eq!(
+		e.to_string(),
+		"loop cannot be detoured: 75 FA cannot be moved"
+	);
+	assert_eq!(unsafe { compact_debug_test_loop(3) }, 0);
+}
+
+/// Attaches a detour while another thread is blocked in a system call among the instructions that
+/// the jump overwrites, which has to go on in the trampoline.
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn test_detour_stopped() {
+	use detour::Detour;
+
+	extern "C" {
+		fn compact_debug_test_read(fd: i32, buf: *mut u8, len: usize) -> isize;
+	}
+
+	extern "C" fn hook(_fd: i32, _buf: *mut u8, _len: usize) -> isize {
+		-2
+	}
+
+	let mut fds = [0; 2];
+	assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+	let [read, write] = fds;
+	let (tx, rx) = std::sync::mpsc::channel();
+	let reader = std::thread::spawn(move || {
+		tx.send(unsafe { libc::syscall(libc::SYS_gettid) }).unwrap();
+		let mut buf = [0u8; 4];
+		let n = unsafe { compact_debug_test_read(read, buf.as_mut_ptr(), buf.len()) };
+		(n, buf[0])
+	});
+
+	// Waits for the reader to be blocked right after the `syscall`.
+	let tid = rx.recv().unwrap();
+	let after = compact_debug_test_read as *const () as usize + 4;
+	let path = format!("/proc/self/task/{tid}/syscall");
+	while !std::fs::read_to_string(&path)
+		.unwrap()
+		.trim_end()
+		.ends_with(&format!(" {after:#x}"))
+	{
+		std::thread::sleep(std::time::Duration::from_millis(1));
+	}
+
+	let function = Function::new(compact_debug_test_read as *const (), "read");
+	let detour = unsafe { Detour::new(function, hook as *const ()) }.unwrap();
+	unsafe { detour.attach() }.unwrap();
+	assert_eq!(unsafe { libc::write(write, b"x".as_ptr().cast(), 1) }, 1);
+	assert_eq!(reader.join().unwrap(), (1, b'x'));
+	assert_eq!(
+		unsafe { compact_debug_test_read(read, std::ptr::null_mut(), 0) },
+		-2
+	);
+	unsafe {
+		libc::close(read);
+		libc::close(write);
+	}
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_stub() {
+	extern "C" {
+		fn compact_debug_test_add(a: u64, b: u64) -> u64;
+		fn compact_debug_test_stub();
+		fn compact_debug_test_lazy();
+	}
+
+	let stub = Function::new(compact_debug_test_stub as *const (), "stub");
+	assert_eq!(stub.ptr, compact_debug_test_add as *const u8);
+	let lazy = Function::new(compact_debug_test_lazy as *const (), "lazy");
+	assert_eq!(lazy.ptr, compact_debug_test_lazy as *const u8);
+}
+
+/// Runs the `prefer_dynamic` example with `std` linked dynamically, both from a position-independent
+/// executable, which refers to `std` directly, and from one that is not, which goes through the PLT.
+#[test]
+#[cfg(target_os = "linux")]
+fn test_prefer_dynamic() {
+	for flags in [
+		"-C prefer-dynamic",
+		"-C prefer-dynamic -C relocation-model=static",
+	] {
+		let manifest_dir = env!("CARGO_MANIFEST_DIR");
+		let out = std::process::Command::new(std::env::var_os("CARGO").unwrap_or("cargo".into()))
+			.args(["run", "--quiet", "--example", "prefer_dynamic", "--target"])
+			.arg(env!("COMPACT_DEBUG_TARGET"))
+			.current_dir(manifest_dir)
+			.env("RUSTFLAGS", flags)
+			.env(
+				"CARGO_TARGET_DIR",
+				format!("{manifest_dir}/target/prefer-dynamic"),
+			)
+			.output()
+			.unwrap();
+		let stderr = String::from_utf8_lossy(&out.stderr);
+		assert!(out.status.success(), "{flags}: {stderr}");
+		assert_eq!(
+			String::from_utf8_lossy(&out.stdout),
+			"Enabled\n(8, 32)\n",
+			"{flags}"
+		);
+	}
+}
+
+/// From before `main`, unless disabled through the environment.
+#[test]
+#[cfg(target_os = "linux")]
+fn test_auto() {
+	let pretty = "(\n    8,\n    32,\n)\n";
+	for (env, expected, warning) in [
+		(None, "(8, 32)\n", ""),
+		(Some("off"), pretty, ""),
+		(Some("structs"), pretty, ""),
+		(Some("all,width=80"), "(8, 32)\n", ""),
+		(
+			Some("tuplez"),
+			pretty,
+			"compact-debug: COMPACT_DEBUG=\"tuplez\" is malformed",
+		),
+	] {
+		let manifest_dir = env!("CARGO_MANIFEST_DIR");
+		let mut cmd =
+			std::process::Command::new(std::env::var_os("CARGO").unwrap_or("cargo".into()));
+		cmd.args(["run", "--quiet", "--example", "auto", "--features", "auto"])
+			.current_dir(manifest_dir)
+			.env("CARGO_TARGET_DIR", format!("{manifest_dir}/target/auto"))
+			.env_remove("COMPACT_DEBUG");
+		if let Some(env) = env {
+			cmd.env("COMPACT_DEBUG", env);
+		}
+		let out = cmd.output().unwrap();
+		let stderr = String::from_utf8_lossy(&out.stderr);
+		assert!(out.status.success(), "{env:?}: {stderr}");
+		assert_eq!(String::from_utf8_lossy(&out.stdout), expected, "{env:?}");
+		assert_eq!(stderr.is_empty(), warning.is_empty(), "{env:?}: {stderr}");
+		assert!(stderr.starts_with(warning), "{env:?}: {stderr}");
+	}
+}
+
+#[test]
+fn test_env() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let config = |targets, max_width| Ok(env::Config { targets, max_width });
+	assert_eq!(env::parse("off"), config(Some(Targets::empty()), None));
+	assert_eq!(env::parse("tuples"), config(Some(Targets::TUPLE), None));
+	assert_eq!(
+		env::parse("structs, maps"),
+		config(Some(Targets::STRUCT | Targets::MAP), None)
+	);
+	assert_eq!(env::parse("all"), config(Some(Targets::all()), None));
+	assert_eq!(env::parse("width=80"), config(None, Some(80)));
+	assert_eq!(
+		env::parse("off,width=80"),
+		config(Some(Targets::empty()), Some(80))
+	);
+	for value in [
+		"tuple",
+		"Tuples",
+		"tuples,",
+		"width=",
+		"width=-1",
+		"off,tuples",
+	] {
+		assert!(env::parse(value).is_err(), "{value}");
+	}
+
+	if !patchable() {
+		return;
+	}
+	let t = (8, 32);
+	let pretty = "(\n    8,\n    32,\n)";
+
+	let e = unsafe { enable_from(Some("tuples,lsits".into())) }.unwrap_err();
+	assert_eq!(
+		e.to_string(),
+		"COMPACT_DEBUG=\"tuples,lsits\" is malformed: `lsits` is not one of `off`, `tuples`, \
+		 `structs`, `lists`, `maps`, `all` or `width=N`"
+	);
+	assert_eq!(format!("{t:#?}"), pretty);
+
+	assert_eq!(unsafe { enable_from(None) }.unwrap(), Targets::empty());
+	assert_eq!(format!("{t:#?}"), "(8, 32)");
+	if !cfg!(target_arch = "x86_64") {
+		let e = unsafe { enable_from(Some("lists,width=3".into())) }.unwrap_err();
+		assert_eq!(
+			e.to_string(),
+			"COMPACT_DEBUG=\"lists,width=3\" is malformed: `width=N` has no effect here, where \
+			 tuples are printed on a single line no matter how wide"
+		);
+		assert_eq!(format!("{:#?}", [1, 2]), "[\n    1,\n    2,\n]");
+		unsafe { enable(false) };
+		return;
+	}
+	assert_eq!(
+		unsafe { enable_from(Some("lists,width=3".into())) }.unwrap(),
+		Targets::TUPLE
+	);
+	assert_eq!(format!("{t:#?}"), pretty);
+	assert_eq!(format!("{:#?}", [1, 2]), "[1, 2]");
+	assert_eq!(MAX_WIDTH.load(Ordering::Relaxed), 3);
+	unsafe { set_max_width(100) };
+	assert_eq!(
+		unsafe { enable_from(Some(" ".into())) }.unwrap(),
+		Targets::LIST
+	);
+	assert_eq!(
+		unsafe { enable_from(Some("off".into())) }.unwrap(),
+		Targets::LIST | Targets::TUPLE
+	);
+	assert_eq!(format!("{t:#?}"), pretty);
+}
+
+/// Like under systemd's `MemoryDenyWriteExecute=yes`, which is irreversible and so needs a process
+/// of its own.
+#[test]
+#[cfg(target_os = "linux")]
+fn test_deny_write_execute() {
+	let manifest_dir = env!("CARGO_MANIFEST_DIR");
+	let out = std::process::Command::new(std::env::var_os("CARGO").unwrap_or("cargo".into()))
+		.args(["run", "--quiet", "--example", "deny_write_execute"])
+		.current_dir(manifest_dir)
+		.env(
+			"CARGO_TARGET_DIR",
+			format!("{manifest_dir}/target/deny-write-execute"),
+		)
+		.output()
+		.unwrap();
+	let stderr = String::from_utf8_lossy(&out.stderr);
+	assert!(out.status.success(), "{stderr}");
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	if stdout.starts_with("unsupported: ") {
+		eprintln!("skipped, since the kernel cannot deny it: {stdout}");
+		return;
+	}
+	assert_eq!(stdout, "(8, 32)\n(\n    8,\n    32,\n)\n");
+}
+
+/// Runs the test `name` once more in a process of its own, unless this is that process, for tests
+/// that change how code is written for good. Returns whether this is that process.
+#[cfg(all(test, target_os = "linux"))]
+fn in_own_process(name: &str) -> bool {
+	if std::env::var_os("COMPACT_DEBUG_TEST_OWN_PROCESS").is_some() {
+		return true;
+	}
+	let out = std::process::Command::new(std::env::current_exe().unwrap())
+		.args([name, "--exact", "--nocapture"])
+		.env("COMPACT_DEBUG_TEST_OWN_PROCESS", "1")
+		.output()
+		.unwrap();
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	let stderr = String::from_utf8_lossy(&out.stderr);
+	assert!(out.status.success(), "{stdout}{stderr}");
+	assert!(stdout.contains(" 1 passed;"), "{stdout}");
+	false
+}
+
+/// Finds the line of `/proc/self/maps` that maps `ptr`.
+#[cfg(all(test, target_os = "linux"))]
+fn mapping(ptr: usize) -> String {
+	let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+	maps.lines()
+		.find(|line| {
+			let (start, end) = line.split_once(' ').unwrap().0.split_once('-').unwrap();
+			let range =
+				usize::from_str_radix(start, 16).unwrap()..usize::from_str_radix(end, 16).unwrap();
+			range.contains(&ptr)
+		})
+		.unwrap()
+		.to_owned()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_alias() {
+	if !in_own_process("test_alias") {
+		return;
+	}
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	ALIASED.store(true, Ordering::Relaxed);
+	if !patchable() {
+		return;
+	}
+
+	let t = (8, 32);
+	unsafe { enable(true) };
+	assert_eq!(format!("{t:#?}"), "(8, 32)");
+
+	// The code is replaced, and never writable.
+	let line = mapping(Targets::TUPLE.functions()[0].ptr as usize);
+	assert!(
+		line.contains(" r-xs ") && line.contains("memfd:compact-debug"),
+		"{line}"
+	);
+
+	unsafe { enable(false) };
+	assert_eq!(format!("{t:#?}"), "(\n    8,\n    32,\n)");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_through_mem() {
+	if !in_own_process("test_through_mem") {
+		return;
+	}
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	ALIASED.store(true, Ordering::Relaxed);
+	THROUGH_MEM.store(true, Ordering::Relaxed);
+	if !patchable() {
+		return;
+	}
+
+	let t = (8, 32);
+	unsafe { enable(true) };
+	assert_eq!(format!("{t:#?}"), "(8, 32)");
+
+	// The code stays where it is, and is never writable.
+	let line = mapping(Targets::TUPLE.functions()[0].ptr as usize);
+	assert!(
+		line.contains(" r-xp ") && !line.contains("memfd:"),
+		"{line}"
+	);
+
+	// Detours cannot be written that way.
+	#[cfg(target_arch = "x86_64")]
+	assert_eq!(width::installed(), Some(false));
+
+	unsafe { enable(false) };
+	assert_eq!(format!("{t:#?}"), "(\n    8,\n    32,\n)");
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_validate() {
+	extern "C" {
+		fn compact_debug_test_hidden();
+		fn compact_debug_test_other();
+		fn compact_debug_test_computed();
+		fn compact_debug_test_same();
+	}
+
+	let hidden = Function::new(compact_debug_test_hidden as *const (), "hidden");
+	let e = unsafe { Site::locate(hidden) }.err().unwrap().to_string();
+	let mut lines = e.lines();
+	assert_eq!(
+		lines.next(),
+		Some("hidden is not as expected: the test is part of another instruction:")
+	);
+	assert_eq!(
+		lines.next().map(str::trim_end),
+		Some("> 0x0000  48 B8 F6 47 12 80 75 00 90 90  mov")
+	);
+	assert_eq!(
+		lines.next().map(str::trim_end),
+		Some("  0x000a  C3                             ret")
+	);
+
+	let other = Function::new(compact_debug_test_other as *const (), "other");
+	let e = unsafe { Site::locate(other) }.err().unwrap().to_string();
+	let lines: Vec<_> = e.lines().map(str::trim_end).collect();
+	assert_eq!(
+		lines[..4],
+		[
+			"other is not as expected: the test is not on the alternate flag:",
+			"> 0x0000  F6 47 30 80                    test",
+			"> 0x0004  75 02                          jne 0x8",
+			"  0x0006  31 C0",
+		]
+	);
+
+	let computed = Function::new(compact_debug_test_computed as *const (), "computed");
+	let e = unsafe { Site::locate(computed) }.err().unwrap().to_string();
+	let lines: Vec<_> = e.lines().map(str::trim_end).collect();
+	assert_eq!(
+		lines[..3],
+		[
+			"computed is not as expected: the tested register does not hold a formatter:",
+			"  0x0000  48 8D 47 08                    lea",
+			"> 0x0004  F6 40 12 80                    test",
+		]
+	);
+
+	let same = Function::new(compact_debug_test_same as *const (), "same");
+	let e = unsafe { Site::locate(same) }.err().unwrap().to_string();
+	let lines: Vec<_> = e.lines().map(str::trim_end).collect();
+	assert_eq!(
+		lines[..4],
+		[
+			"same is not as expected: the branch lands on the same code as it falls through to:",
+			"  0x0000  48 8B 07                       mov",
+			"> 0x0003  F6 40 12 80                    test",
+			"> 0x0007  75 00                          jne 0x9",
+		]
+	);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_tuple_policy() {
+	let _lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	if !patchable() {
+		return;
+	}
+
+	#[allow(dead_code)]
+	#[derive(Debug)]
+	struct Address(u32);
+	#[allow(dead_code)]
+	#[derive(Debug)]
+	struct Span(u32, u32);
+	#[allow(dead_code)]
+	#[derive(Debug)]
+	struct Wide(String, u8);
+
+	let value = (Address(30016), Some(Span(1, 2)), (5,));
+	let pretty = format!("{value:#?}");
+
+	unsafe { enable(true) };
+
+	assert_eq!(
+		format!("{value:#?}"),
+		"(Address(30016), Some(Span(1, 2)), (5,))"
+	);
+
+	assert!(unsafe { set_tuple_policy(TuplePolicy::Named) });
+	assert_eq!(
+		format!("{value:#?}"),
+		"(\n    Address(30016),\n    Some(Span(1, 2)),\n    (\n        5,\n    ),\n)"
+	);
+
+	unsafe { set_tuple_policy(TuplePolicy::Anonymous) };
+	assert_eq!(
+		format!("{value:#?}"),
+		"(Address(\n    30016,\n), Some(\n    Span(\n        1,\n        2,\n    ),\n), (5,))"
+	);
+
+	// Derived impls go through the hosts, which are patched rather than detoured, and so are not
+	// held to the width limit.
+	unsafe { set_tuple_policy(TuplePolicy::All) };
+	unsafe { set_max_width(10) };
+	let wide = Wide("x".repeat(20), 1);
+	assert_eq!(format!("{wide:#?}"), format!("{wide:?}"));
+	unsafe { set_max_width(100) };
+
+	unsafe { enable(false) };
+
+	unsafe { set_tuple_policy(TuplePolicy::Named) };
+	assert_eq!(format!("{value:#?}"), pretty);
+	unsafe { set_tuple_policy(TuplePolicy::All) };
+}
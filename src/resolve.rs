@@ -0,0 +1,153 @@
+//! Finding functions by name in the images loaded in the process.
+//!
+//! Taking the address of a function only ever finds the copy that this crate links to, and only
+//! the one that the compiler chose to hand out, while LTO may leave other copies behind under names
+//! of their own. The symbol tables of each image are read from the file it was loaded from, since
+//! those of `std` are not exported, and every function in them whose demangled name matches is
+//! returned.
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::demangle::{demangle, same_path};
+use crate::elf::{self, Symbol};
+use crate::images::{self, Image};
+use crate::targets::{Function, Targets};
+
+/// A function found in the symbol tables of one of the images loaded in the process, as returned
+/// by [`definitions`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Definition {
+	/// The demangled name, such as `<core::fmt::builders::DebugTuple>::field`.
+	pub name: String,
+	/// The file the image was loaded from.
+	pub image: PathBuf,
+	/// Where the function is loaded.
+	pub address: usize,
+	/// How long the function is in bytes, or zero if that is not known.
+	pub size: usize,
+}
+
+/// Finds every definition of a function in the executable and the shared libraries loaded in the
+/// process, by its demangled path, such as `<core::fmt::builders::DebugTuple>::field`.
+///
+/// Inherent methods match whether or not their type is wrapped in `<...>`, since legacy mangling
+/// leaves that out, and v0 mangling puts it in. Images without a symbol table, such as stripped
+/// ones, are not found.
+///
+/// Images are only listed on Linux. Elsewhere, this finds nothing.
+pub fn definitions(path: &str) -> Vec<Definition> {
+	let mut found = Vec::new();
+	for image in images::images() {
+		let file = image.read();
+		found.extend(in_image(&image, &elf::functions(&file), path));
+	}
+	found
+}
+
+/// Finds the definitions of a function in a single image, given the functions in its file.
+pub(crate) fn in_image(image: &Image, functions: &[Symbol], path: &str) -> Vec<Definition> {
+	// Every identifier appears in the mangled name at least once, which rules out most symbols
+	// without demangling them.
+	let idents: Vec<_> = path
+		.split(|c: char| !c.is_alphanumeric() && c != '_')
+		.filter(|s| !s.is_empty() && s.is_ascii())
+		.collect();
+	let mut found = Vec::new();
+	for f in functions {
+		let contains = |s: &str| f.name.windows(s.len()).any(|w| w == s.as_bytes());
+		if !idents.iter().all(|s| contains(s)) {
+			continue;
+		}
+		let Some(name) = std::str::from_utf8(f.name).ok().and_then(demangle) else {
+			continue;
+		};
+		let address = f.value.wrapping_add(image.base);
+		if same_path(&name, path) && image.contains(address) {
+			found.push(Definition {
+				name,
+				image: image.path.clone(),
+				address,
+				size: f.size,
+			});
+		}
+	}
+	found
+}
+
+/// The definitions of the functions to patch and of their hosts in the image that this crate uses
+/// the `std` of, by name, along with their sizes.
+///
+/// The image is only read once, for all of them at a time.
+fn own() -> &'static [(&'static str, usize, usize)] {
+	static OWN: OnceLock<Vec<(&str, usize, usize)>> = OnceLock::new();
+	OWN.get_or_init(|| {
+		let mut functions = Vec::new();
+		for (_, target) in Targets::all().iter() {
+			functions.extend(target.functions());
+			functions.extend(target.hosts().iter().map(|&name| Function {
+				ptr: std::ptr::null(),
+				name,
+			}));
+		}
+		let Some(image) = images::images()
+			.into_iter()
+			.find(|i| i.contains(functions[0].ptr as usize))
+		else {
+			return Vec::new();
+		};
+		let file = image.read();
+		let symbols = elf::functions(&file);
+		let mut found = Vec::new();
+		for f in functions {
+			for definition in in_image(&image, &symbols, &f.path()) {
+				let entry = (f.name, definition.address, definition.size);
+				if !found.contains(&entry) {
+					found.push(entry);
+				}
+			}
+		}
+		found
+	})
+}
+
+/// Finds the copies of one of the functions of this crate's `std` in the same image, other than the
+/// one that its address leads to.
+pub(crate) fn copies(function: Function) -> Vec<Function> {
+	own()
+		.iter()
+		.filter(|&&(name, ptr, _)| name == function.name && ptr != function.ptr as usize)
+		.map(|&(name, ptr, _)| Function {
+			ptr: ptr as *const u8,
+			name,
+		})
+		.collect()
+}
+
+/// The size of one of the functions of this crate's `std`, if the symbol table tells.
+///
+/// Functions with identical code may have been merged into one under several names, which all
+/// have the same size.
+pub(crate) fn size(function: Function) -> Option<usize> {
+	own()
+		.iter()
+		.find(|&&(_, ptr, size)| ptr == function.ptr as usize && size != 0)
+		.map(|&(.., size)| size)
+}
+
+/// Finds the hosts of the functions of a single target in the image that this crate uses the `std`
+/// of, along with their sizes, see [`Targets::hosts`].
+pub(crate) fn hosts(target: Targets) -> Vec<(Function, usize)> {
+	own()
+		.iter()
+		.filter(|(name, ..)| target.hosts().contains(name))
+		.map(|&(name, ptr, size)| {
+			let function = Function {
+				ptr: ptr as *const u8,
+				name,
+			};
+			(function, size)
+		})
+		.collect()
+}
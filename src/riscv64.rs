@@ -0,0 +1,102 @@
+//! Patch for riscv64.
+//!
+//! The `Debug` builders load the formatter's flags, mask out the alternate bit with `andi`, and
+//! then branch on the result with `beqz`/`bnez`, which are usually compressed. Like on aarch64,
+//! the patch makes the `andi` read the zero register instead of the loaded flags, so the branch
+//! always takes the non-pretty path, and the original is recovered by putting back the register
+//! from the load. The `andi` is always the 32-bit encoding, since the compressed one has neither
+//! room for a separate source register nor for a mask past bit 5.
+//!
+//! Instructions are a mix of 16 and 32 bits, so the function is decoded from the start rather than
+//! scanned at every offset.
+
+use std::arch::asm;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+
+use crate::scan::SCAN_LIMIT;
+use crate::{PatchError, Site};
+
+#[path = "scan/riscv64.rs"]
+mod scan;
+
+pub use scan::Insn;
+
+/// Finds every `andi` of the alternate flag in a function, which is `len` bytes long if that is known.
+pub unsafe fn find_patch_sites(
+	function: *const u8,
+	len: Option<usize>,
+	name: &'static str,
+) -> Result<Vec<Site>, PatchError> {
+	let code = unsafe { std::slice::from_raw_parts(function, len.unwrap_or(SCAN_LIMIT)) };
+	let found = scan::scan(code);
+	if found.is_empty() {
+		return Err(PatchError::UnsupportedToolchain { function: name });
+	}
+	Ok(found
+		.into_iter()
+		.map(|f| Site {
+			ptr: function.wrapping_add(f.offset) as *mut u32,
+			original: f.original,
+			patched: f.patched,
+		})
+		.collect())
+}
+
+/// Returns where a PLT entry jumps to.
+///
+/// That is `auipc t3, %hi`, `ld t3, %lo(t3)` and `jalr t1, t3`.
+pub unsafe fn follow_stub(function: *const u8) -> Option<*const u8> {
+	let code = unsafe { std::slice::from_raw_parts(function.cast::<u16>(), 6) };
+	let word = |i: usize| code[2 * i] as u32 | (code[2 * i + 1] as u32) << 16;
+	let [auipc, ld, jalr] = [word(0), word(1), word(2)];
+	if auipc & 0xFFF != 0xE17 || ld & 0xFFFFF != 0xE3E03 || jalr != 0xE0367 {
+		return None;
+	}
+	let hi = (auipc & !0xFFF) as i32 as isize;
+	let lo = (ld as i32 >> 20) as isize;
+	let slot = (function as usize).wrapping_add_signed(hi + lo);
+	Some(unsafe { (slot as *const *const u8).read() })
+}
+
+/// Splits an instruction that is only 2-byte aligned into its halves.
+unsafe fn halves(ptr: *mut Insn) -> [&'static AtomicU16; 2] {
+	let ptr = ptr as *mut u16;
+	unsafe { [AtomicU16::from_ptr(ptr), AtomicU16::from_ptr(ptr.add(1))] }
+}
+
+pub unsafe fn load(ptr: *mut Insn) -> Insn {
+	if ptr.is_aligned() {
+		return unsafe { AtomicU32::from_ptr(ptr) }.load(Ordering::SeqCst);
+	}
+	let [lo, hi] = unsafe { halves(ptr) };
+	lo.load(Ordering::SeqCst) as u32 | (hi.load(Ordering::SeqCst) as u32) << 16
+}
+
+/// Stores the instruction at `ptr` by writing it to `via`, which is either the same or an alias,
+/// and then makes sure that all harts will see it: `fence.i` only covers the current one, so the
+/// kernel is asked to take care of the rest.
+///
+/// An `andi` that is only 2-byte aligned is stored one half at a time. The source register
+/// straddles both halves, so a hart may briefly see it test some other register, but never
+/// anything other than an `andi` into the same register, which at worst picks the wrong layout.
+pub unsafe fn store(ptr: *mut Insn, via: *mut Insn, insn: Insn) {
+	const SYS_RISCV_FLUSH_ICACHE: usize = 259;
+	unsafe {
+		if via.is_aligned() {
+			AtomicU32::from_ptr(via).store(insn, Ordering::SeqCst);
+		} else {
+			let [lo, hi] = halves(via);
+			lo.store(insn as u16, Ordering::SeqCst);
+			hi.store((insn >> 16) as u16, Ordering::SeqCst);
+		}
+		asm!("fence.i", options(nostack, preserves_flags));
+		asm!(
+			"ecall",
+			in("a7") SYS_RISCV_FLUSH_ICACHE,
+			inlateout("a0") ptr => _,
+			in("a1") ptr.add(1),
+			in("a2") 0,
+			options(nostack, preserves_flags),
+		);
+	}
+}
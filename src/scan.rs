@@ -0,0 +1,11 @@
+//! What the scans in `scan/`, one for each architecture, have in common.
+//!
+//! The scans only look at the bytes they are given, and do not depend on the rest of the crate, so
+//! that the build script can run the very same scans on the code it compiles. Each stops at the
+//! `ret` that ends the function, so that it does not wander into whatever code happens to follow
+//! it. A function may end in a tail call rather than a `ret` though, so they are never given more
+//! than the function itself when the symbol table tells how long it is, and no more than
+//! [`SCAN_LIMIT`] bytes otherwise.
+
+/// How far into a function to look before giving up, unless its size is known.
+pub const SCAN_LIMIT: usize = 0x200;
@@ -0,0 +1,100 @@
+//! Scan for the test of the alternate flag on aarch64.
+//!
+//! What the scans have in common is described in `src/scan.rs`.
+
+pub type Insn = u32;
+
+/// A test, at `offset` bytes into the function.
+pub struct Found {
+	pub offset: usize,
+	pub original: Insn,
+	pub patched: Insn,
+}
+
+/// How many instructions may separate the load of the flags from the test.
+const LOOKBEHIND: usize = 4;
+
+const RET: u32 = 0xD65F03C0;
+
+const ZR: u32 = 31;
+
+/// Finds every test of the alternate flag in a function.
+///
+/// The scan stops at the `ret` that ends the function. Early returns are told apart by a
+/// conditional branch jumping past them.
+pub fn scan(code: &[u8]) -> Vec<Found> {
+	// Instructions are always little-endian, whatever the data is.
+	let code: Vec<u32> = code
+		.chunks_exact(4)
+		.map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+		.collect();
+	let mut found = Vec::new();
+	let mut end = 0;
+	for i in 0..code.len() {
+		if code[i] == RET && i >= end {
+			break;
+		}
+		if let Some(target) = branch_target(code[i], i) {
+			end = end.max(target);
+		}
+		let Some((field, shift)) = match_test(&code[i..]) else {
+			continue;
+		};
+		let Some(rt) = code[i.saturating_sub(LOOKBEHIND)..i]
+			.iter()
+			.rev()
+			.find_map(|&c| match_load(c))
+		else {
+			continue;
+		};
+		let reg = field >> shift & 31;
+		if reg != rt && reg != ZR {
+			continue;
+		}
+		found.push(Found {
+			offset: i * 4,
+			original: field & !(31 << shift) | rt << shift,
+			patched: field | ZR << shift,
+		});
+	}
+	found
+}
+/// Returns the index of the instruction that a conditional branch at index `i` jumps to.
+fn branch_target(insn: u32, i: usize) -> Option<usize> {
+	let offset = if insn & 0xFF000010 == 0x54000000 || insn & 0x7E000000 == 0x34000000 {
+		// b.cond, cbz, cbnz
+		((insn >> 5 & 0x7FFFF) << 13) as i32 >> 13
+	} else if insn & 0x7E000000 == 0x36000000 {
+		// tbz, tbnz
+		((insn >> 5 & 0x3FFF) << 18) as i32 >> 18
+	} else {
+		return None;
+	};
+	i.checked_add_signed(offset as isize)
+}
+
+/// Matches `ldrb`, `ldrh` or `ldr` with an immediate offset, returning the destination register.
+fn match_load(insn: u32) -> Option<u32> {
+	(insn & 0x3FC00000 == 0x39400000).then_some(insn & 31)
+}
+
+/// Matches a test of a single bit other than bit 0, which is what `bool`s are tested with,
+/// returning the instruction that holds the tested register and where in it that register is.
+fn match_test(code: &[u32]) -> Option<(u32, u32)> {
+	let &[insn, ref rest @ ..] = code else {
+		return None;
+	};
+	// tbz, tbnz
+	if insn & 0x7E000000 == 0x36000000 {
+		let bit = (insn >> 26 & 0x20) | (insn >> 19 & 0x1F);
+		return (bit != 0).then_some((insn, 0));
+	}
+	// tst #imm, with a single-bit immediate, followed by b.eq or b.ne
+	if insn & 0x7F80001F == 0x7200001F {
+		let (immr, imms) = (insn >> 16 & 0x3F, insn >> 10 & 0x3F);
+		let branch = *rest.first()?;
+		let is_beq_bne = branch & 0xFF00001E == 0x54000000;
+		return (imms == 0 && immr != 0 && is_beq_bne).then_some((insn, 5));
+	}
+	None
+}
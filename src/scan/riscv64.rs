@@ -0,0 +1,127 @@
+//! Scan for the `andi` of the alternate flag on riscv64.
+//!
+//! What the scans have in common is described in `src/scan.rs`.
+
+pub type Insn = u32;
+
+/// An `andi`, at `offset` bytes into the function.
+pub struct Found {
+	pub offset: usize,
+	pub original: Insn,
+	pub patched: Insn,
+}
+
+/// How many instructions may separate the load of the flags from the `andi`, and the `andi` from
+/// the branch.
+const LOOKAROUND: usize = 4;
+
+const RET: u32 = 0x00008067; // jalr zero, 0(ra)
+const C_RET: u32 = 0x8082; // c.jr ra
+
+const ZERO: u32 = 0;
+
+/// Finds every `andi` of the alternate flag in a function, up to the `ret` that ends it.
+pub fn scan(code: &[u8]) -> Vec<Found> {
+	let code: Vec<u16> = code
+		.chunks_exact(2)
+		.map(|c| u16::from_le_bytes([c[0], c[1]]))
+		.collect();
+	let insns = decode(&code);
+	let mut found = Vec::new();
+	for (i, &(offset, insn)) in insns.iter().enumerate() {
+		let Some((rd, rs1)) = match_andi(insn) else {
+			continue;
+		};
+		let before = &insns[i.saturating_sub(LOOKAROUND)..i];
+		let Some(rt) = before.iter().rev().find_map(|&(_, insn)| match_load(insn)) else {
+			continue;
+		};
+		let after = insns[i + 1..].iter().take(LOOKAROUND);
+		if rs1 != rt && rs1 != ZERO || !after.into_iter().any(|&(_, insn)| is_branch_on(insn, rd)) {
+			continue;
+		}
+		found.push(Found {
+			offset,
+			original: insn & !(31 << 15) | rt << 15,
+			patched: insn & !(31 << 15) | ZERO << 15,
+		});
+	}
+	found
+}
+
+/// Splits the code into instructions along with their byte offsets, up to the `ret` that ends the
+/// function. Early returns are told apart by a conditional branch jumping past them.
+fn decode(code: &[u16]) -> Vec<(usize, u32)> {
+	let mut insns = Vec::new();
+	let mut end = 0;
+	let mut i = 0;
+	while i < code.len() {
+		let insn = if code[i] & 3 == 3 {
+			let Some(&hi) = code.get(i + 1) else {
+				break;
+			};
+			code[i] as u32 | (hi as u32) << 16
+		} else {
+			code[i] as u32
+		};
+		if (insn == RET || insn == C_RET) && i * 2 >= end {
+			break;
+		}
+		if let Some(offset) = branch_offset(insn) {
+			end = end.max((i * 2).saturating_add_signed(offset));
+		}
+		insns.push((i * 2, insn));
+		i += if code[i] & 3 == 3 { 2 } else { 1 };
+	}
+	insns
+}
+
+/// Returns how many bytes a conditional branch jumps by.
+fn branch_offset(insn: u32) -> Option<isize> {
+	let offset = if insn & 0x7F == 0x63 {
+		// beq, bne, blt, bge, bltu, bgeu
+		let imm = (insn >> 31 & 1) << 12
+			| (insn >> 7 & 1) << 11
+			| (insn >> 25 & 0x3F) << 5
+			| (insn >> 8 & 0xF) << 1;
+		(imm << 19) as i32 >> 19
+	} else if insn & 3 == 1 && matches!(insn >> 13 & 7, 6 | 7) {
+		// c.beqz, c.bnez
+		let imm = (insn >> 12 & 1) << 8
+			| (insn >> 5 & 3) << 6
+			| (insn >> 2 & 1) << 5
+			| (insn >> 10 & 3) << 3
+			| (insn >> 3 & 3) << 1;
+		(imm << 23) as i32 >> 23
+	} else {
+		return None;
+	};
+	Some(offset as isize)
+}
+
+/// Matches `lb`, `lh`, `lw`, `lbu`, `lhu` or `lwu`, returning the destination register.
+fn match_load(insn: u32) -> Option<u32> {
+	let funct3 = insn >> 12 & 7;
+	(insn & 0x7F == 0x03 && !matches!(funct3, 3 | 7)).then_some(insn >> 7 & 31)
+}
+
+/// Matches `andi` with a single-bit mask other than bit 0, which is what `bool`s are tested with,
+/// returning its destination and source registers.
+fn match_andi(insn: u32) -> Option<(u32, u32)> {
+	let mask = (insn as i32 >> 20) as u32;
+	let is_andi = insn & 0x707F == 0x7013;
+	(is_andi && mask.is_power_of_two() && mask != 1).then_some((insn >> 7 & 31, insn >> 15 & 31))
+}
+
+/// Whether this is a `beqz` or `bnez` on the given register, compressed or not.
+fn is_branch_on(insn: u32, reg: u32) -> bool {
+	if insn & 3 == 3 {
+		// beq/bne reg, zero
+		let (funct3, rs1, rs2) = (insn >> 12 & 7, insn >> 15 & 31, insn >> 20 & 31);
+		insn & 0x7F == 0x63 && funct3 <= 1 && rs1 == reg && rs2 == ZERO
+	} else {
+		// c.beqz/c.bnez, which can only name x8..x15
+		let (funct3, rs1) = (insn >> 13 & 7, (insn >> 7 & 7) + 8);
+		insn & 3 == 1 && matches!(funct3, 6 | 7) && rs1 == reg
+	}
+}
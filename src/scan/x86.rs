@@ -0,0 +1,136 @@
+//! Scan for the branch on x86, both 64-bit and 32-bit.
+//!
+//! What the scans have in common is described in `src/scan.rs`.
+
+pub type Insn = u8;
+
+/// A branch, at `offset` bytes into the function.
+pub struct Found {
+	pub offset: usize,
+	/// Where the `test` that the branch is on starts.
+	pub test: usize,
+	pub original: Insn,
+	pub patched: Insn,
+}
+
+/// How many moves may separate the `test` from the branch.
+const MAX_MOVES: usize = 2;
+
+/// Finds every `jne` that selects the pretty-printing path in a function.
+///
+/// The branch is recognized as a single-bit `test` on memory, followed by either a short
+/// (`75 xx`) or near (`0F 85 xx xx xx xx`) `jne`, or the same `jne` after it has been patched. The
+/// compiler sometimes schedules a move or two in between, which is fine since those do not touch
+/// the flags. The scan stops at the `ret` that ends the function.
+///
+/// If nothing is found, the error holds the two bytes that followed the first `test`, if there was
+/// one.
+pub fn scan(code: &[u8], x86_64: bool) -> Result<Vec<Found>, Option<[u8; 2]>> {
+	let mut found = Vec::new();
+	let mut unexpected = None;
+	let mut i = 0;
+	while i < code.len() {
+		if code[i] == 0xC3 && code.get(i + 1).is_none_or(|&b| b == 0xCC) {
+			break;
+		}
+		let Some(mut len) = match_test(&code[i..], x86_64) else {
+			i += 1;
+			continue;
+		};
+		for _ in 0..MAX_MOVES {
+			match match_move(&code[i + len..], x86_64) {
+				Some(n) => len += n,
+				None => break,
+			}
+		}
+		let offset = i + len;
+		match code[offset..] {
+			[ORIGINAL | PATCHED, ..] => found.push(Found {
+				offset,
+				test: i,
+				original: ORIGINAL,
+				patched: PATCHED,
+			}),
+			[0x0F, ORIGINAL_NEAR | PATCHED_NEAR, ..] => found.push(Found {
+				offset: offset + 1,
+				test: i,
+				original: ORIGINAL_NEAR,
+				patched: PATCHED_NEAR,
+			}),
+			[a, b, ..] => {
+				unexpected.get_or_insert([a, b]);
+			}
+			_ => {}
+		}
+		i += len;
+	}
+	if found.is_empty() {
+		return Err(unexpected);
+	}
+	Ok(found)
+}
+
+/// Skips a REX prefix, which on x86 is `inc`/`dec` instead.
+fn rex_len(code: &[u8], x86_64: bool) -> usize {
+	usize::from(x86_64 && matches!(code.first(), Some(0x40..=0x4F)))
+}
+
+/// Returns the length of a ModRM byte along with the SIB byte and displacement that follow it, and
+/// whether it refers to memory.
+pub fn modrm_len(code: &[u8]) -> Option<(usize, bool)> {
+	let modrm = *code.first()?;
+	let (md, rm) = (modrm >> 6, modrm & 7);
+	if md == 3 {
+		return Some((1, false));
+	}
+	let mut len = 1;
+	let mut base = rm;
+	if rm == 4 {
+		base = *code.get(1)? & 7;
+		len += 1; // SIB
+	}
+	len += match md {
+		0 if rm == 5 || base == 5 => 4,
+		0 => 0,
+		1 => 1,
+		_ => 4,
+	};
+	Some((len, true))
+}
+
+/// Matches `test r/m8, imm8` with a memory operand and a single-bit immediate, returning its
+/// length.
+fn match_test(code: &[u8], x86_64: bool) -> Option<usize> {
+	let i = rex_len(code, x86_64);
+	let [0xF6, modrm, ..] = *code.get(i..)? else {
+		return None;
+	};
+	if modrm >> 3 & 7 != 0 {
+		return None;
+	}
+	let (len, true) = modrm_len(&code[i + 1..])? else {
+		return None;
+	};
+	let i = i + 1 + len;
+	let imm = *code.get(i)?;
+	imm.is_power_of_two().then_some(i + 1)
+}
+
+/// Matches `mov`, `movzx`, `movsx` or `lea`, none of which affect the flags, returning its length.
+fn match_move(code: &[u8], x86_64: bool) -> Option<usize> {
+	let i = rex_len(code, x86_64);
+	let opcode = match *code.get(i..)? {
+		[0x88..=0x8B | 0x8D, ..] => 1,
+		[0x0F, 0xB6 | 0xB7 | 0xBE | 0xBF, ..] => 2,
+		_ => return None,
+	};
+	let (len, _) = modrm_len(code.get(i + opcode..)?)?;
+	Some(i + opcode + len)
+}
+
+// `test` always clears OF, so turning `jne` into `jo` makes the branch never taken while keeping
+// its displacement, which means the original can be restored by flipping the opcode back.
+pub const ORIGINAL: u8 = 0x75; // jne rel8
+pub const PATCHED: u8 = 0x70; // jo rel8
+pub const ORIGINAL_NEAR: u8 = 0x85; // 0F 85: jne rel32
+pub const PATCHED_NEAR: u8 = 0x80; // 0F 80: jo rel32
@@ -0,0 +1,140 @@
+//! Checks that the patch does what it should, right after it is applied.
+//!
+//! Finding the branch only shows that the code looks right. To be sure that it also behaves right
+//! on this build of `std`, a few values are formatted with `{:#?}` and compared to what they should
+//! look like. Most of the values call the builders through pointers that the compiler cannot see
+//! through, since with LTO, any direct call may well be inlined, and thereby bypass the patched
+//! functions. A few go through the impls of `std` itself instead, which is what most output goes
+//! through, so that the patch fails as a whole if those bypass it.
+//!
+//! Derived impls are checked on their own, since they go through functions that have copies of the
+//! builders inlined into them, which are only patched if they can be found by name. Those that
+//! cannot are reported rather than failing the patch as a whole.
+
+use std::fmt::{self, Debug, DebugList, DebugTuple, Formatter};
+use std::hint::black_box;
+
+use crate::{PatchError, Targets};
+
+/// A tuple with a name, which may be empty, and fields.
+struct Tuple<'a>(&'a str, &'a [&'a dyn Debug]);
+
+impl Debug for Tuple<'_> {
+	fn fmt<'a, 'b>(&self, f: &'a mut Formatter<'b>) -> fmt::Result {
+		let field: for<'r> fn(
+			&'r mut DebugTuple<'a, 'b>,
+			&dyn Debug,
+		) -> &'r mut DebugTuple<'a, 'b> = black_box(DebugTuple::field);
+		let finish: fn(&mut DebugTuple<'a, 'b>) -> fmt::Result = black_box(DebugTuple::finish);
+		let mut tuple = f.debug_tuple(self.0);
+		for value in self.1 {
+			field(&mut tuple, value);
+		}
+		finish(&mut tuple)
+	}
+}
+
+struct List<'a>(&'a [&'a dyn Debug]);
+
+impl Debug for List<'_> {
+	fn fmt<'a, 'b>(&self, f: &'a mut Formatter<'b>) -> fmt::Result {
+		let entry: for<'r> fn(&'r mut DebugList<'a, 'b>, &dyn Debug) -> &'r mut DebugList<'a, 'b> =
+			black_box(DebugList::entry);
+		let finish: fn(&mut DebugList<'a, 'b>) -> fmt::Result = black_box(DebugList::finish);
+		let mut list = f.debug_list();
+		for value in self.0 {
+			entry(&mut list, value);
+		}
+		finish(&mut list)
+	}
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct Derived1(u8);
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct Derived2(u8, u8);
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct Derived3(u8, u8, u8);
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct Derived4(u8, u8, u8, u8);
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct Derived5(u8, u8, u8, u8, u8);
+
+#[allow(dead_code)]
+#[derive(Debug)]
+struct Derived6(u8, u8, u8, u8, u8, u8);
+
+/// Formats the probes, given that `targets` are enabled, which must include [`Targets::TUPLE`].
+pub fn run(targets: Targets) -> Result<(), PatchError> {
+	let list = match targets.contains(Targets::LIST) {
+		true => "[(1, 2)]",
+		false => "[\n    (1, 2),\n]",
+	};
+	// The comma after a single field is only added outside of pretty mode, by `finish`, which has to
+	// agree with `field` on which mode it is in.
+	let mut probes: Vec<(&dyn Debug, &'static str)> = vec![
+		(&Tuple("Newtype", &[&5]), "Newtype(5)"),
+		(&Tuple("Pair", &[&8, &32]), "Pair(8, 32)"),
+		(&List(&[&Tuple("", &[&1, &2])]), list),
+		(&Tuple("", &[&5]), "(5,)"),
+		(&Tuple("", &[&Tuple("", &[&5])]), "((5,),)"),
+		(&Tuple("", &[&1, &2, &3]), "(1, 2, 3)"),
+		(&Tuple("Unit", &[]), "Unit"),
+		(&Tuple("", &[]), ""),
+		(&(1, 2), "(1, 2)"),
+	];
+	// `Option` is derived, and so goes through the hosts, which the policy may leave alone.
+	if crate::named_tuples() {
+		probes.push((&Some(1), "Some(1)"));
+	}
+	for (value, expected) in probes {
+		let found = format(value);
+		if found != expected {
+			return Err(PatchError::SelfTestFailed { expected, found });
+		}
+	}
+	Ok(())
+}
+
+/// Formats a derived impl for each of the hosts of [`Targets::TUPLE`], given that it is enabled, and
+/// returns the hosts that still print over several lines.
+///
+/// Hosts that are left alone on purpose, since the policy leaves out tuples with a name, are not
+/// returned.
+pub fn unpatched() -> Vec<&'static str> {
+	if !crate::named_tuples() {
+		return Vec::new();
+	}
+	let probes: [(&dyn Debug, &str); 6] = [
+		(&Derived1(1), "Derived1(1)"),
+		(&Derived2(1, 2), "Derived2(1, 2)"),
+		(&Derived3(1, 2, 3), "Derived3(1, 2, 3)"),
+		(&Derived4(1, 2, 3, 4), "Derived4(1, 2, 3, 4)"),
+		(&Derived5(1, 2, 3, 4, 5), "Derived5(1, 2, 3, 4, 5)"),
+		(&Derived6(1, 2, 3, 4, 5, 6), "Derived6(1, 2, 3, 4, 5, 6)"),
+	];
+	// In the same order as the hosts.
+	let hosts = Targets::TUPLE.hosts();
+	hosts
+		.iter()
+		.zip(probes)
+		.filter(|(_, (value, expected))| format(*value) != *expected)
+		.map(|(&host, _)| host)
+		.collect()
+}
+
+fn format(value: &dyn Debug) -> String {
+	#[cfg(target_arch = "x86_64")]
+	return crate::width::unlimited(|| format!("{value:#?}"));
+	#[cfg(not(target_arch = "x86_64"))]
+	return format!("{value:#?}");
+}
@@ -0,0 +1,226 @@
+//! Stopping the other threads of the process while a detour is attached, for x86_64 Linux.
+//!
+//! The jump of a detour usually takes the place of more than one instruction. A thread that was
+//! interrupted after the first of them, or is blocked in a system call among them, would go on in
+//! the middle of the jump once it is written. So every other thread is sent a signal first, whose
+//! handler holds it until the jump has been written, and then moves it to the same offset in the
+//! trampoline, where the same instructions are, if it was interrupted among them.
+//!
+//! Once attached, the instructions stay moved, and a thread that handles the signal late, such as
+//! when it blocks the signal for a while, is still moved. Threads that do not handle it in time
+//! make attaching fail instead.
+//!
+//! `SIGURG` is used, like the Go runtime does, since it is ignored by default, and debuggers pass it
+//! on without stopping. Any that this crate did not send are passed on to the handler that was
+//! there before.
+
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+const SIGNAL: libc::c_int = libc::SIGURG;
+
+/// The `si_code` of signals sent with `tgkill`, which `libc` does not have.
+const SI_TKILL: libc::c_int = -6;
+
+/// How long to wait for the other threads to stop before giving up.
+const TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How many ranges of instructions can be moved, which is more than there are functions to
+/// detour.
+const MAX_MOVED: usize = 32;
+
+/// The ranges of instructions that were moved, as where they start, how long they are, and where
+/// they were moved to. Only the first [`MOVED_LEN`] are in use, and they are never changed again.
+static MOVED: [[AtomicUsize; 3]; MAX_MOVED] =
+	[const { [const { AtomicUsize::new(0) }; 3] }; MAX_MOVED];
+static MOVED_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether threads are held in the handler.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+/// How many threads are in the handler.
+static PARKED: AtomicUsize = AtomicUsize::new(0);
+
+/// The handler that was there before, which signals that this crate did not send are passed on to.
+static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// Serializes writers.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Runs `write`, which replaces the instructions in `from` with a jump, while every other thread is
+/// stopped, and moves the threads that were stopped among them to the same offset from `to`.
+///
+/// # Errors
+/// Fails without running `write` if the handler cannot be installed, if the threads cannot be
+/// listed, or if any of them does not stop in time, such as because it blocks the signal.
+pub unsafe fn stopped(from: Range<usize>, to: usize, write: impl FnOnce()) -> io::Result<()> {
+	let _lock = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+	install()?;
+	let len = MOVED_LEN.load(Ordering::SeqCst);
+	if len == MAX_MOVED {
+		return Err(io::ErrorKind::OutOfMemory.into());
+	}
+
+	STOPPED.store(true, Ordering::SeqCst);
+	let result = stop_all();
+	if result.is_ok() {
+		// Threads may already be moved before the jump is written, which does no harm, since the
+		// trampoline is complete.
+		let [start, moved_len, moved_to] = &MOVED[len];
+		start.store(from.start, Ordering::SeqCst);
+		moved_len.store(from.len(), Ordering::SeqCst);
+		moved_to.store(to, Ordering::SeqCst);
+		MOVED_LEN.store(len + 1, Ordering::SeqCst);
+		write();
+	}
+	STOPPED.store(false, Ordering::SeqCst);
+	result
+}
+
+/// Signals every other thread, and waits until all of them are held in the handler.
+///
+/// Nothing is allocated once the first thread is stopped, since it may be holding a lock of the
+/// allocator.
+fn stop_all() -> io::Result<()> {
+	let pid = unsafe { libc::getpid() };
+	let own = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+	let deadline = Instant::now() + TIMEOUT;
+	let mut tasks = Vec::new();
+	list_tasks(&mut tasks)?;
+	// Room for threads that are started in the meantime.
+	let capacity = tasks.len() * 2 + 64;
+	tasks.reserve(capacity);
+	let mut signaled = Vec::with_capacity(capacity);
+	// Threads are only started by threads that are running, so once all that were listed are
+	// stopped, listing them again finds them all.
+	let mut all_stopped = false;
+	loop {
+		list_tasks(&mut tasks)?;
+		let mut started = false;
+		for &tid in &tasks {
+			if tid == own || signaled.contains(&tid) {
+				continue;
+			}
+			started = true;
+			if signaled.len() == signaled.capacity() {
+				return Err(io::ErrorKind::OutOfMemory.into());
+			}
+			// A thread that exits in the meantime is not waited for.
+			if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, SIGNAL) } == 0 {
+				signaled.push(tid);
+			}
+		}
+		if all_stopped && !started {
+			return Ok(());
+		}
+		let alive = signaled.iter().filter(|t| tasks.contains(t)).count();
+		all_stopped = !started && PARKED.load(Ordering::SeqCst) >= alive;
+		if Instant::now() > deadline {
+			return Err(io::ErrorKind::TimedOut.into());
+		}
+		std::thread::yield_now();
+	}
+}
+
+/// Lists the threads of the process into `tasks`, without growing it if it has room for them all.
+fn list_tasks(tasks: &mut Vec<libc::pid_t>) -> io::Result<()> {
+	tasks.clear();
+	let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+	let fd = unsafe { libc::open(c"/proc/self/task".as_ptr(), flags) };
+	if fd < 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let result = (|| {
+		let mut buf = [0u8; 4096];
+		loop {
+			let n = unsafe { libc::syscall(libc::SYS_getdents64, fd, buf.as_mut_ptr(), buf.len()) };
+			if n < 0 {
+				return Err(io::Error::last_os_error());
+			}
+			if n == 0 {
+				return Ok(());
+			}
+			// Each entry is an inode, an offset, its length, a type, and a NUL-terminated name.
+			let mut entry = &buf[..n as usize];
+			while entry.len() >= 19 {
+				let len = u16::from_ne_bytes([entry[16], entry[17]]) as usize;
+				let name = &entry[19..len];
+				let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+				if let Some(tid) = std::str::from_utf8(name).ok().and_then(|s| s.parse().ok()) {
+					if tasks.len() == tasks.capacity() && tasks.capacity() != 0 {
+						return Err(io::ErrorKind::OutOfMemory.into());
+					}
+					tasks.push(tid);
+				}
+				entry = &entry[len..];
+			}
+		}
+	})();
+	unsafe { libc::close(fd) };
+	result
+}
+
+/// Installs the handler, unless that already happened.
+fn install() -> io::Result<()> {
+	if PREVIOUS.get().is_some() {
+		return Ok(());
+	}
+	let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+	action.sa_sigaction = handler as extern "C" fn(_, _, _) as libc::sighandler_t;
+	action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+	unsafe { libc::sigemptyset(&mut action.sa_mask) };
+	let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+	if unsafe { libc::sigaction(SIGNAL, &action, &mut previous) } != 0 {
+		return Err(io::Error::last_os_error());
+	}
+	let _ = PREVIOUS.set(previous);
+	Ok(())
+}
+
+extern "C" fn handler(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+	let ours = unsafe { (*info).si_code == SI_TKILL && (*info).si_pid() == libc::getpid() };
+	if !ours {
+		unsafe { chain(signal, info, context) };
+		return;
+	}
+
+	let errno = unsafe { *libc::__errno_location() };
+	PARKED.fetch_add(1, Ordering::SeqCst);
+	while STOPPED.load(Ordering::SeqCst) {
+		unsafe { libc::sched_yield() };
+	}
+	let context = unsafe { &mut *context.cast::<libc::ucontext_t>() };
+	let rip = &mut context.uc_mcontext.gregs[libc::REG_RIP as usize];
+	for [start, len, to] in &MOVED[..MOVED_LEN.load(Ordering::SeqCst)] {
+		let offset = (*rip as usize).wrapping_sub(start.load(Ordering::SeqCst));
+		// At the start, the thread has not executed any of them yet, and takes the jump.
+		if (1..len.load(Ordering::SeqCst)).contains(&offset) {
+			*rip = (to.load(Ordering::SeqCst) + offset) as i64;
+		}
+	}
+	// Code written by another thread is only certain to be seen after a serializing instruction.
+	std::arch::x86_64::__cpuid(0);
+	PARKED.fetch_sub(1, Ordering::SeqCst);
+	unsafe { *libc::__errno_location() = errno };
+}
+
+/// Passes a signal on to the handler that was there before.
+unsafe fn chain(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+	let Some(previous) = PREVIOUS.get() else {
+		return;
+	};
+	match previous.sa_sigaction {
+		libc::SIG_DFL | libc::SIG_IGN => {}
+		f if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+			let f: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+				unsafe { std::mem::transmute(f) };
+			f(signal, info, context);
+		}
+		f => {
+			let f: extern "C" fn(libc::c_int) = unsafe { std::mem::transmute(f) };
+			f(signal);
+		}
+	}
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
+
+/// A function in `std` that contains a branch on the alternate flag to patch.
+#[derive(Clone, Copy)]
+pub struct Function {
+	pub ptr: *const u8,
+	pub name: &'static str,
+}
+
+impl Function {
+	/// Refers to the function at `ptr`, or wherever it jumps to if it is only a stub.
+	///
+	/// When `std` is linked dynamically, the address of one of its functions may be that of a stub
+	/// in the PLT, which jumps to the function in the shared library through its GOT entry.
+	pub fn new(ptr: *const (), name: &'static str) -> Function {
+		let mut ptr = ptr as *const u8;
+		// A stub may lead to another one, but anything beyond a few is more likely a loop.
+		for _ in 0..4 {
+			// SAFETY: functions are always readable, and only as much is read as a stub takes up.
+			match unsafe { crate::arch::follow_stub(ptr) } {
+				Some(target) => ptr = target,
+				None => break,
+			}
+		}
+		Function { ptr, name }
+	}
+
+	/// The full path of the function, as it is demangled.
+	pub fn path(&self) -> String {
+		// The builders are re-exported from `core::fmt`, but defined in a module of their own.
+		match self.name.starts_with("Formatter::") {
+			true => format!("core::fmt::{}", self.name),
+			false => format!("core::fmt::builders::{}", self.name),
+		}
+	}
+}
+
+/// Which of the `Debug` builders to print on a single line.
+///
+/// Combine them with `|`, and pass them to [`enable_with`](crate::enable_with) or
+/// [`scoped_with`](crate::scoped_with).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Targets(u8);
+
+impl Targets {
+	/// `DebugTuple`, used by tuples, tuple structs and tuple variants, such as `Some(1)`.
+	pub const TUPLE: Targets = Targets(1 << 0);
+	/// `DebugStruct`, used by structs and struct variants, such as `B { x: 8, y: 32 }`.
+	pub const STRUCT: Targets = Targets(1 << 1);
+	/// `DebugList` and `DebugSet`, used by slices, `Vec`, `HashSet` and the like, such as `[8, 32]`.
+	pub const LIST: Targets = Targets(1 << 2);
+	/// `DebugMap`, used by `HashMap`, `BTreeMap` and the like, such as `{1: 2, 3: 4}`.
+	pub const MAP: Targets = Targets(1 << 3);
+
+	pub(crate) const COUNT: usize = 4;
+	const NAMES: [&'static str; Targets::COUNT] = ["TUPLE", "STRUCT", "LIST", "MAP"];
+
+	/// No targets at all.
+	pub const fn empty() -> Targets {
+		Targets(0)
+	}
+
+	/// Every target.
+	pub const fn all() -> Targets {
+		Targets((1 << Targets::COUNT) - 1)
+	}
+
+	pub const fn is_empty(self) -> bool {
+		self.0 == 0
+	}
+
+	/// Whether all of `other` is in `self`.
+	pub const fn contains(self, other: Targets) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// The individual targets, along with their index.
+	pub(crate) fn iter(self) -> impl Iterator<Item = (usize, Targets)> {
+		(0..Targets::COUNT)
+			.map(|i| (i, Targets(1 << i)))
+			.filter(move |&(_, t)| self.contains(t))
+	}
+
+	/// The functions to patch for a single target.
+	pub(crate) fn functions(self) -> Vec<Function> {
+		use std::fmt::{DebugList, DebugMap, DebugSet, DebugStruct, DebugTuple};
+		match self {
+			// `finish` adds the comma that tells `(1,)` apart from `(1)`, and `finish_non_exhaustive`
+			// lays out the `..`, both differently in pretty mode.
+			Targets::TUPLE => vec![
+				Function::new(DebugTuple::field as *const (), "DebugTuple::field"),
+				Function::new(DebugTuple::finish as *const (), "DebugTuple::finish"),
+				Function::new(
+					DebugTuple::finish_non_exhaustive as *const (),
+					"DebugTuple::finish_non_exhaustive",
+				),
+			],
+			// `finish` separates the closing brace from the last field with a space, but only in
+			// non-pretty mode, so it has to agree with `field` on which mode it is in, and so does
+			// `finish_non_exhaustive` with the `..`.
+			Targets::STRUCT => vec![
+				Function::new(DebugStruct::field as *const (), "DebugStruct::field"),
+				Function::new(DebugStruct::finish as *const (), "DebugStruct::finish"),
+				Function::new(
+					DebugStruct::finish_non_exhaustive as *const (),
+					"DebugStruct::finish_non_exhaustive",
+				),
+			],
+			// Both share their implementation, which is inlined into each of them. Their
+			// `finish_non_exhaustive` lays out the `..` differently in pretty mode, like that of
+			// `DebugTuple`.
+			Targets::LIST => vec![
+				Function::new(DebugList::entry as *const (), "DebugList::entry"),
+				Function::new(DebugSet::entry as *const (), "DebugSet::entry"),
+				Function::new(
+					DebugList::finish_non_exhaustive as *const (),
+					"DebugList::finish_non_exhaustive",
+				),
+				Function::new(
+					DebugSet::finish_non_exhaustive as *const (),
+					"DebugSet::finish_non_exhaustive",
+				),
+			],
+			// `entry` does not go through `key` and `value` entirely, but has its own copy of at
+			// least one of them.
+			Targets::MAP => vec![
+				Function::new(DebugMap::key as *const (), "DebugMap::key"),
+				Function::new(DebugMap::value as *const (), "DebugMap::value"),
+				Function::new(DebugMap::entry as *const (), "DebugMap::entry"),
+				Function::new(
+					DebugMap::finish_non_exhaustive as *const (),
+					"DebugMap::finish_non_exhaustive",
+				),
+			],
+			_ => unreachable!(),
+		}
+	}
+
+	/// The functions that `std` builds with copies of its own of those of a single target inlined
+	/// into them, and that can only be found by name.
+	///
+	/// Derived impls of tuple structs and structs go through these rather than through `DebugTuple`
+	/// and `DebugStruct`, one for each number of fields up to five, and one for any number beyond.
+	pub(crate) fn hosts(self) -> &'static [&'static str] {
+		match self {
+			Targets::TUPLE => &[
+				"Formatter::debug_tuple_field1_finish",
+				"Formatter::debug_tuple_field2_finish",
+				"Formatter::debug_tuple_field3_finish",
+				"Formatter::debug_tuple_field4_finish",
+				"Formatter::debug_tuple_field5_finish",
+				"Formatter::debug_tuple_fields_finish",
+			],
+			Targets::STRUCT => &[
+				"Formatter::debug_struct_field1_finish",
+				"Formatter::debug_struct_field2_finish",
+				"Formatter::debug_struct_field3_finish",
+				"Formatter::debug_struct_field4_finish",
+				"Formatter::debug_struct_field5_finish",
+				"Formatter::debug_struct_fields_finish",
+			],
+			_ => &[],
+		}
+	}
+}
+
+impl BitOr for Targets {
+	type Output = Targets;
+	fn bitor(self, rhs: Targets) -> Targets {
+		Targets(self.0 | rhs.0)
+	}
+}
+
+impl BitOrAssign for Targets {
+	fn bitor_assign(&mut self, rhs: Targets) {
+		self.0 |= rhs.0;
+	}
+}
+
+impl BitAnd for Targets {
+	type Output = Targets;
+	fn bitand(self, rhs: Targets) -> Targets {
+		Targets(self.0 & rhs.0)
+	}
+}
+
+impl Not for Targets {
+	type Output = Targets;
+	fn not(self) -> Targets {
+		Targets(!self.0 & Targets::all().0)
+	}
+}
+
+impl fmt::Debug for Targets {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("Targets(")?;
+		for (n, (i, _)) in self.iter().enumerate() {
+			if n != 0 {
+				f.write_str(" | ")?;
+			}
+			f.write_str(Targets::NAMES[i])?;
+		}
+		f.write_str(")")
+	}
+}
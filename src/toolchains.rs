@@ -0,0 +1,82 @@
+//! The toolchains that the patch is known to work with.
+//!
+//! This is only data, so that the build script can check it too.
+
+/// A toolchain that the patch is known to work with, along with where the branch in
+/// `DebugTuple::field` is in the `std` it ships with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct Toolchain {
+	/// The release, as reported by `rustc -vV`.
+	pub release: &'static str,
+	/// The commit hash the release was built from.
+	pub commit: &'static str,
+	/// The target triple, since each target has its own build of `std`.
+	pub target: &'static str,
+	/// The offset of the branch into `DebugTuple::field`.
+	pub offset: usize,
+	/// The instruction there, as bytes.
+	pub original: &'static [u8],
+	/// What the instruction is patched to.
+	pub patched: &'static [u8],
+}
+
+const fn entry(
+	(release, commit): (&'static str, &'static str),
+	target: &'static str,
+	offset: usize,
+	original: &'static [u8],
+	patched: &'static [u8],
+) -> Toolchain {
+	Toolchain {
+		release,
+		commit,
+		target,
+		offset,
+		original,
+		patched,
+	}
+}
+
+const RUST_1_95_0: (&str, &str) = ("1.95.0", "59807616e1fa2540724bfbac14d7976d7e4a3860");
+const NIGHTLY_2026_05_19: (&str, &str) =
+	("1.97.0-nightly", "e50aa6fba4e63ab34c72bf9acfd2c307c1155d1a");
+
+/// Every known toolchain, oldest first.
+pub static TOOLCHAINS: &[Toolchain] = &[
+	entry(
+		RUST_1_95_0,
+		"x86_64-unknown-linux-gnu",
+		0x43,
+		&[0x75],
+		&[0x70],
+	),
+	entry(
+		RUST_1_95_0,
+		"i686-unknown-linux-gnu",
+		0x30,
+		&[0x75],
+		&[0x70],
+	),
+	entry(
+		RUST_1_95_0,
+		"aarch64-unknown-linux-gnu",
+		0x3C,
+		&[0x68, 0x02, 0x38, 0x37], // tbnz w8, #7
+		&[0x7F, 0x02, 0x38, 0x37], // tbnz wzr, #7
+	),
+	entry(
+		RUST_1_95_0,
+		"riscv64gc-unknown-linux-gnu",
+		0x28,
+		&[0x93, 0xF5, 0x05, 0x08], // andi a1, a1, 128
+		&[0x93, 0x75, 0x00, 0x08], // andi a1, zero, 128
+	),
+	entry(
+		NIGHTLY_2026_05_19,
+		"x86_64-unknown-linux-gnu",
+		0x43,
+		&[0x75],
+		&[0x70],
+	),
+];
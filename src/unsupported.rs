@@ -0,0 +1,25 @@
+//! Stand-in for architectures that there is no patch for yet.
+
+use crate::{PatchError, Site};
+
+pub type Insn = u8;
+
+pub unsafe fn find_patch_sites(
+	_function: *const u8,
+	_len: Option<usize>,
+	_name: &'static str,
+) -> Result<Vec<Site>, PatchError> {
+	Err(PatchError::UnsupportedArch)
+}
+
+pub unsafe fn follow_stub(_function: *const u8) -> Option<*const u8> {
+	None
+}
+
+pub unsafe fn load(_ptr: *mut Insn) -> Insn {
+	unreachable!()
+}
+
+pub unsafe fn store(_ptr: *mut Insn, _via: *mut Insn, _insn: Insn) {
+	unreachable!()
+}
@@ -0,0 +1,369 @@
+//! Width-aware layout of `DebugTuple`, for x86_64.
+//!
+//! Flipping the branch in `DebugTuple::field` can only ever print a tuple on a single line. To fall
+//! back to the usual layout for tuples that would not fit, `field` and `finish` are detoured to
+//! hooks that render each field into a buffer, and only lay out the whole tuple in `finish`, once
+//! its width is known. The hooks only do so while the branch is flipped, and defer to the original
+//! functions otherwise, so the branch still decides whether the patch is enabled.
+//!
+//! The same goes for tuples that [`set_tuple_policy`](crate::set_tuple_policy) leaves out, which the
+//! hooks tell apart by whether the builder has an empty name.
+//!
+//! Derived impls do not go through `field` and `finish`, but through the hosts of `DebugTuple`, see
+//! [`Targets::hosts`], which have copies of them inlined. Those are only found by name, and unlike
+//! functions whose address is taken, LTO is free to change how they take their arguments, so they
+//! are never detoured. Their branches are flipped like any other, which prints derived tuples on a
+//! single line no matter how wide, and left alone while the policy leaves out tuples with a name.
+//!
+//! The hooks need the formatter of a `DebugTuple`, and need to point a `Formatter` at a buffer of
+//! their own, neither of which `std` offers. Both are found by checking where a known pointer ends
+//! up in a builder and a formatter created for that purpose.
+
+use std::cell::{Cell, RefCell};
+use std::fmt::{self, Debug, DebugTuple, Formatter};
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+
+use crate::arch::find_word;
+use crate::detour::Detour;
+use crate::targets::Function;
+use crate::{PatchError, PatchState, Site, Targets, TuplePolicy, MAX_WIDTH, TUPLE_POLICY};
+
+type Field =
+	for<'r, 'a, 'b> fn(&'r mut DebugTuple<'a, 'b>, &dyn Debug) -> &'r mut DebugTuple<'a, 'b>;
+type Finish = fn(&mut DebugTuple) -> fmt::Result;
+
+/// Everything the hooks need, which is set before any of them are attached.
+static HOOKS: OnceLock<Hooks> = OnceLock::new();
+
+struct Hooks {
+	/// The original functions, called through their trampolines.
+	field: Field,
+	finish: Finish,
+	finish_non_exhaustive: Finish,
+	/// The branch in `DebugTuple::field`, which decides whether the hooks do anything.
+	site: Site,
+	/// The offset of the `&mut Formatter` in a `DebugTuple`.
+	formatter: usize,
+	/// The offset of the `&mut dyn Write` in a `Formatter`.
+	buf: usize,
+	/// The offset of the flag in a `DebugTuple` that says whether it has an empty name.
+	empty_name: usize,
+}
+
+/// Detours `DebugTuple`, unless that already happened, returning whether it worked.
+///
+/// Failing to do so only means that tuples are printed on a single line no matter how wide, or
+/// whether the policy leaves them out, so the error itself is not reported.
+pub fn install() -> bool {
+	*INSTALLED.get_or_init(|| unsafe { try_install() }.is_ok())
+}
+
+static INSTALLED: OnceLock<bool> = OnceLock::new();
+
+unsafe fn try_install() -> Result<(), PatchError> {
+	let unsupported = |function| PatchError::UnsupportedToolchain { function };
+	let detour = |ptr, name, hook| unsafe { Detour::new(Function::new(ptr, name), hook) };
+	let field = detour(
+		DebugTuple::field as *const (),
+		"DebugTuple::field",
+		self::field as Field as *const (),
+	)?;
+	let finish = detour(
+		DebugTuple::finish as *const (),
+		"DebugTuple::finish",
+		self::finish as Finish as *const (),
+	)?;
+	let finish_non_exhaustive = detour(
+		DebugTuple::finish_non_exhaustive as *const (),
+		"DebugTuple::finish_non_exhaustive",
+		self::finish_non_exhaustive as Finish as *const (),
+	)?;
+	let function = Targets::TUPLE.functions()[0];
+	let hooks = Hooks {
+		field: unsafe { field.original() },
+		finish: unsafe { finish.original() },
+		finish_non_exhaustive: unsafe { finish_non_exhaustive.original() },
+		site: unsafe { Site::locate(function) }?.swap_remove(0),
+		formatter: probe_formatter().ok_or_else(|| unsupported("DebugTuple"))?,
+		buf: probe_buf().ok_or_else(|| unsupported("Formatter"))?,
+		empty_name: probe_empty_name().ok_or_else(|| unsupported("DebugTuple"))?,
+	};
+	let _ = HOOKS.set(hooks);
+
+	// `finish` does not do anything different until `field` has buffered something, so attaching
+	// it first means that a failure halfway leaves everything working.
+	unsafe {
+		finish.attach()?;
+		finish_non_exhaustive.attach()?;
+		field.attach()?;
+	}
+	Ok(())
+}
+
+/// Whether detouring `DebugTuple` worked, or `None` if that was not tried yet.
+pub fn installed() -> Option<bool> {
+	INSTALLED.get().copied()
+}
+
+fn hooks() -> &'static Hooks {
+	HOOKS.get().expect("hooks attached before they were set")
+}
+
+impl Hooks {
+	fn enabled(&self) -> bool {
+		unsafe { self.site.state() == PatchState::Enabled }
+	}
+
+	/// Returns the formatter that a tuple writes to.
+	unsafe fn formatter<'f>(&self, tuple: *mut DebugTuple) -> &'f mut Formatter<'static> {
+		unsafe {
+			&mut **tuple
+				.cast::<u8>()
+				.add(self.formatter)
+				.cast::<*mut Formatter>()
+		}
+	}
+
+	/// Whether a tuple has an empty name, which makes a single field print as `(1,)`.
+	unsafe fn empty_name(&self, tuple: *const DebugTuple) -> bool {
+		unsafe { *tuple.cast::<u8>().add(self.empty_name) != 0 }
+	}
+
+	/// Formats `value` into `out` rather than wherever `fmt` writes to, keeping all its options.
+	unsafe fn render(
+		&self,
+		fmt: &mut Formatter,
+		out: &mut String,
+		value: &dyn Debug,
+	) -> fmt::Result {
+		struct Restore(*mut *mut dyn fmt::Write, *mut dyn fmt::Write);
+
+		impl Drop for Restore {
+			fn drop(&mut self) {
+				unsafe { self.0.write(self.1) };
+			}
+		}
+
+		let buf = unsafe { (fmt as *mut Formatter).cast::<u8>().add(self.buf) }.cast();
+		let _restore = Restore(buf, unsafe { buf.replace(out as &mut dyn fmt::Write) });
+		value.fmt(fmt)
+	}
+}
+
+/// A tuple whose fields are being buffered.
+struct Pending {
+	tuple: *const (),
+	fields: Vec<String>,
+	result: fmt::Result,
+}
+
+thread_local! {
+	/// The tuples being buffered on this thread, innermost last.
+	///
+	/// Builders are nested like the `fmt` calls that create them, so the tuple being worked on is
+	/// normally the last one. Ones that were never finished, because formatting a field panicked or
+	/// the builder was dropped, are discarded once a tuple before them is worked on again.
+	static PENDING: RefCell<Vec<Pending>> = const { RefCell::new(Vec::new()) };
+
+	/// Whether the width limit and the tuple policy are ignored on this thread, see [`unlimited`].
+	static UNLIMITED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` with tuples printed on a single line no matter how wide they are and whether they have a
+/// name, like on the other architectures.
+pub fn unlimited<R>(f: impl FnOnce() -> R) -> R {
+	struct Reset(bool);
+
+	impl Drop for Reset {
+		fn drop(&mut self) {
+			UNLIMITED.set(self.0);
+		}
+	}
+
+	let _reset = Reset(UNLIMITED.replace(true));
+	f()
+}
+
+/// Returns how wide the content of a tuple may be to be printed on a single line, or `None` if the policy leaves it
+/// out.
+fn max_width(anonymous: bool) -> Option<usize> {
+	if UNLIMITED.get() {
+		return Some(usize::MAX);
+	}
+	let policy = match TUPLE_POLICY.load(Ordering::Relaxed) {
+		p if p == TuplePolicy::Named as u8 => TuplePolicy::Named,
+		p if p == TuplePolicy::Anonymous as u8 => TuplePolicy::Anonymous,
+		_ => TuplePolicy::All,
+	};
+	let allowed = match policy {
+		TuplePolicy::All => true,
+		TuplePolicy::Named => !anonymous,
+		TuplePolicy::Anonymous => anonymous,
+	};
+	allowed.then(|| MAX_WIDTH.load(Ordering::Relaxed))
+}
+
+/// Finds a tuple that is being buffered, and discards any that were left behind after it.
+fn find(pending: &mut Vec<Pending>, tuple: *const ()) -> Option<&mut Pending> {
+	let i = pending.iter().rposition(|p| p.tuple == tuple)?;
+	pending.truncate(i + 1);
+	pending.last_mut()
+}
+
+fn field<'r, 'a, 'b>(
+	tuple: &'r mut DebugTuple<'a, 'b>,
+	value: &dyn Debug,
+) -> &'r mut DebugTuple<'a, 'b> {
+	let hooks = hooks();
+	let key = tuple as *const DebugTuple as *const ();
+	let fmt = unsafe { hooks.formatter(tuple) };
+	let buffering = PENDING.with_borrow_mut(|p| find(p, key).is_some());
+	if !(buffering || fmt.alternate() && hooks.enabled()) {
+		return (hooks.field)(tuple, value);
+	}
+
+	let mut out = String::new();
+	let result = unsafe { hooks.render(fmt, &mut out, value) };
+	PENDING.with_borrow_mut(|pending| {
+		if find(pending, key).is_none() {
+			pending.push(Pending {
+				tuple: key,
+				fields: Vec::new(),
+				result: Ok(()),
+			});
+		}
+		let p = pending.last_mut().unwrap();
+		p.fields.push(out);
+		p.result = p.result.and(result);
+	});
+	tuple
+}
+
+fn finish(tuple: &mut DebugTuple) -> fmt::Result {
+	finish_with(tuple, false)
+}
+
+fn finish_non_exhaustive(tuple: &mut DebugTuple) -> fmt::Result {
+	finish_with(tuple, true)
+}
+
+fn finish_with(tuple: &mut DebugTuple, non_exhaustive: bool) -> fmt::Result {
+	let hooks = hooks();
+	let key = tuple as *const DebugTuple as *const ();
+	let pending = PENDING.with_borrow_mut(|p| find(p, key).is_some().then(|| p.pop().unwrap()));
+	match pending {
+		Some(pending) => {
+			let anonymous = unsafe { hooks.empty_name(tuple) };
+			let comma = !non_exhaustive && pending.fields.len() == 1 && anonymous;
+			write(
+				unsafe { hooks.formatter(tuple) },
+				pending,
+				non_exhaustive,
+				comma,
+				max_width(anonymous),
+			)
+		}
+		None if non_exhaustive => (hooks.finish_non_exhaustive)(tuple),
+		None => (hooks.finish)(tuple),
+	}
+}
+
+/// Writes out the buffered fields of a tuple, on a single line if none of the lines from its opening
+/// to its closing parenthesis would be wider than `max_width`, and like `std` does otherwise. On a single line, `comma` adds the trailing
+/// comma of a 1-tuple.
+fn write(
+	fmt: &mut Formatter,
+	pending: Pending,
+	non_exhaustive: bool,
+	comma: bool,
+	max_width: Option<usize>,
+) -> fmt::Result {
+	pending.result?;
+	let mut fields = pending.fields;
+	if non_exhaustive {
+		fields.push("..".to_string());
+	}
+
+	if let Some(max_width) = max_width {
+		let line = format!("({}{})", fields.join(", "), if comma { "," } else { "" });
+		if line.lines().all(|l| l.chars().count() <= max_width) {
+			return fmt.write_str(&line);
+		}
+	}
+
+	fmt.write_str("(\n")?;
+	for (i, field) in fields.iter().enumerate() {
+		for line in field.split_inclusive('\n') {
+			fmt.write_str("    ")?;
+			fmt.write_str(line)?;
+		}
+		let last = non_exhaustive && i == fields.len() - 1;
+		fmt.write_str(if last { "\n" } else { ",\n" })?;
+	}
+	fmt.write_str(")")
+}
+
+/// Finds where a `DebugTuple` keeps its formatter.
+fn probe_formatter() -> Option<usize> {
+	struct Probe<'a>(&'a Cell<Option<usize>>);
+
+	impl Debug for Probe<'_> {
+		fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+			let ptr = f as *mut Formatter as usize;
+			let tuple = f.debug_tuple("");
+			self.0.set(find_word(&tuple, ptr));
+			Ok(())
+		}
+	}
+
+	let found = Cell::new(None);
+	fmt::write(&mut String::new(), format_args!("{:?}", Probe(&found))).ok()?;
+	found.get()
+}
+
+/// Finds where a `DebugTuple` keeps whether it has an empty name, by comparing a builder with an
+/// empty name to one with a name.
+fn probe_empty_name() -> Option<usize> {
+	struct Probe<'a>(&'a Cell<Option<usize>>);
+
+	impl Debug for Probe<'_> {
+		fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+			let empty = bytes(&f.debug_tuple(""));
+			let named = bytes(&f.debug_tuple("x"));
+			let mut found = empty.iter().zip(&named).enumerate();
+			let mut found = found.by_ref().filter(|&(_, (&a, &b))| (a, b) == (1, 0));
+			let found = found.next().filter(|_| found.next().is_none());
+			self.0.set(found.map(|(i, _)| i));
+			Ok(())
+		}
+	}
+
+	let found = Cell::new(None);
+	fmt::write(&mut String::new(), format_args!("{:?}", Probe(&found))).ok()?;
+	found.get()
+}
+
+/// Finds where a `Formatter` keeps the writer it writes to.
+fn probe_buf() -> Option<usize> {
+	struct Probe<'a>(&'a Cell<Option<usize>>, usize);
+
+	impl Debug for Probe<'_> {
+		fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+			self.0.set(find_word(f, self.1));
+			Ok(())
+		}
+	}
+
+	let found = Cell::new(None);
+	let mut out = String::new();
+	let ptr = &mut out as *mut String as usize;
+	fmt::write(&mut out, format_args!("{:?}", Probe(&found, ptr))).ok()?;
+	found.get()
+}
+
+/// Returns a copy of the bytes of `value`.
+fn bytes<T>(value: &T) -> Vec<u8> {
+	let ptr = value as *const T as *const u8;
+	unsafe { std::slice::from_raw_parts(ptr, size_of::<T>()) }.to_vec()
+}
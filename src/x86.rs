@@ -0,0 +1,472 @@
+//! Patch for x86, both 64-bit and 32-bit.
+//!
+//! The code `rustc` generates for the `Debug` builders is much the same on both, except that the
+//! 32-bit build passes arguments on the stack and addresses everything through `ebx`, which moves
+//! the branch around. The scan does not care about that, but the `test` before it only has a REX
+//! prefix on x86_64, where `40`..`4F` are not `inc`/`dec`.
+//!
+//! The scan only goes by a handful of bytes, which could just as well be part of some other
+//! instruction, so everything it finds is checked by decoding the function from its start. A
+//! branch is only patched if both it and the `test` are whole instructions, the `test` is on the
+//! alternate flag of a `Formatter` that was loaded from a builder or passed in, and the branch lands
+//! on an instruction too, and on other code than it falls through to.
+
+use std::cell::RefCell;
+use std::fmt::{self, Debug, Formatter, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+use crate::decode::decode;
+use crate::scan::SCAN_LIMIT;
+use crate::{Hex, PatchError, Site};
+
+#[path = "scan/x86.rs"]
+pub mod scan;
+
+pub use scan::Insn;
+use scan::{Found, ORIGINAL, ORIGINAL_NEAR, PATCHED, PATCHED_NEAR};
+
+const X86_64: bool = cfg!(target_arch = "x86_64");
+
+/// The opcodes that may follow the `test`, for error messages.
+const EXPECTED: &[&[u8]] = &[
+	&[ORIGINAL],
+	&[PATCHED],
+	&[0x0F, ORIGINAL_NEAR],
+	&[0x0F, PATCHED_NEAR],
+];
+
+/// Finds every `jne` that selects the pretty-printing path in a function, which is `len` bytes long if that is known.
+///
+/// Branches that do not hold up to a closer look are left out. If that leaves none, the error
+/// lists the instructions around the first of them.
+pub unsafe fn find_patch_sites(
+	function: *const u8,
+	len: Option<usize>,
+	name: &'static str,
+) -> Result<Vec<Site>, PatchError> {
+	let code = unsafe { std::slice::from_raw_parts(function, len.unwrap_or(SCAN_LIMIT)) };
+	let found = match scan::scan(code, X86_64) {
+		Ok(found) => found,
+		Err(Some(found)) => {
+			return Err(PatchError::UnexpectedBytes {
+				function: name,
+				found: found.to_vec(),
+				expected: EXPECTED,
+			})
+		}
+		Err(None) => return Err(PatchError::UnsupportedToolchain { function: name }),
+	};
+
+	// A detour may have been written over the start, which would throw the decoding off.
+	#[cfg_attr(not(target_arch = "x86_64"), allow(unused_mut))]
+	let mut code = code.to_vec();
+	#[cfg(target_arch = "x86_64")]
+	crate::detour::undo(function, &mut code);
+	let starts = starts(&code);
+
+	let mut sites = Vec::new();
+	let mut invalid = None;
+	for f in found {
+		match validate(&code, &starts, &f) {
+			Ok(()) => sites.push(Site {
+				ptr: function.wrapping_add(f.offset) as *mut u8,
+				original: f.original,
+				patched: f.patched,
+			}),
+			Err(reason) => {
+				invalid.get_or_insert_with(|| PatchError::Unverified {
+					function: name,
+					reason,
+					listing: listing(&code, &starts, &f),
+				});
+			}
+		}
+	}
+	match invalid {
+		Some(e) if sites.is_empty() => Err(e),
+		_ => Ok(sites),
+	}
+}
+
+/// Decodes the code from the start, returning where each instruction starts.
+fn starts(code: &[u8]) -> Vec<usize> {
+	let mut starts = Vec::new();
+	let mut i = 0;
+	while let Some(insn) = decode(&code[i..]) {
+		starts.push(i);
+		i += insn.len;
+	}
+	starts
+}
+
+/// Checks that a branch found by the scan is what it looks like.
+fn validate(code: &[u8], starts: &[usize], f: &Found) -> Result<(), &'static str> {
+	let is_start = |i| starts.binary_search(&i).is_ok();
+	let branch = if code[f.offset] == ORIGINAL_NEAR || code[f.offset] == PATCHED_NEAR {
+		f.offset - 1
+	} else {
+		f.offset
+	};
+	if !is_start(f.test) {
+		return Err("the test is part of another instruction");
+	}
+	if !is_start(branch) {
+		return Err("the branch is part of another instruction");
+	}
+	let flag = alternate_flag().ok_or("the alternate flag cannot be found in a formatter")?;
+	let (base, disp, imm) = test_operands(&code[f.test..]).ok_or("the test is not on memory")?;
+	if (disp, imm) != flag {
+		return Err("the test is not on the alternate flag");
+	}
+	formatter_source(code, starts, f.test, base)?;
+	let insn = decode(&code[branch..]).ok_or("the branch cannot be decoded")?;
+	let fall_through = branch + insn.len;
+	let target = fall_through.checked_add_signed(insn.rel.unwrap_or(0) as isize);
+	let Some(target) = target.filter(|&t| is_start(t)) else {
+		return Err("the branch does not land on an instruction");
+	};
+	// Either way would then print the same, so this is not the branch between the two layouts.
+	if target == fall_through || straight(code, target) == straight(code, fall_through) {
+		return Err("the branch lands on the same code as it falls through to");
+	}
+	Ok(())
+}
+
+/// Checks that the register the `test` is on holds a formatter, which is either loaded from where a
+/// builder keeps it, or is the first argument, as in the methods of `Formatter` that builders are
+/// inlined into.
+///
+/// A register that was copied from another one, or loaded from the stack, is followed back to where
+/// that got it from, since those methods keep the builder on the stack, with the formatter stored
+/// into it from the argument.
+///
+/// The instruction that set the register is taken to be the last `mov` or `lea` into it before the
+/// `test`, in the order the code is laid out rather than along the branches leading to it, and
+/// likewise the last `mov` into a stack slot.
+fn formatter_source(
+	code: &[u8],
+	starts: &[usize],
+	test: usize,
+	base: u8,
+) -> Result<(), &'static str> {
+	const NOT_FORMATTER: &str = "the tested register does not hold a formatter";
+	let fields = formatter_fields().ok_or("the formatter cannot be found in a builder")?;
+	let mut end = test;
+	let mut target = Operand::Reg(base);
+	// Anything beyond a few copies is more likely something else entirely.
+	for hop in 0..4 {
+		let Some((at, insn, source)) = last_write(
+			code,
+			&starts[..starts.partition_point(|&i| i < end)],
+			target,
+		) else {
+			return Err(match hop {
+				0 => "the tested register is not set before the test",
+				_ => NOT_FORMATTER,
+			});
+		};
+		if insn.opcode == 0x8D || insn.wide != X86_64 {
+			return Err(NOT_FORMATTER);
+		}
+		let is_argument = match source {
+			Operand::Reg(reg) => X86_64 && reg == RDI,
+			Operand::Mem { base, disp } => !X86_64 && base == EBP && disp == 8,
+		};
+		let is_field = matches!(source, Operand::Mem { base, disp }
+			if base != ESP && base != EBP && usize::try_from(disp).is_ok_and(|d| fields.contains(&d)));
+		if is_argument || is_field {
+			return Ok(());
+		}
+		if matches!(source, Operand::Mem { base, .. } if base != ESP && base != EBP) {
+			return Err(NOT_FORMATTER);
+		}
+		(end, target) = (at, source);
+	}
+	Err(NOT_FORMATTER)
+}
+
+/// Finds the last `mov` or `lea` among the instructions at `starts` that writes to `target`,
+/// returning where it is, its operands, and what it copies.
+fn last_write(
+	code: &[u8],
+	starts: &[usize],
+	target: Operand,
+) -> Option<(usize, Operands, Operand)> {
+	for &i in starts.iter().rev() {
+		let Some(insn) = operands(&code[i..]) else {
+			continue;
+		};
+		let source = match (insn.opcode, insn.rm, target) {
+			(0x8B | 0x8D, _, Operand::Reg(reg)) if insn.reg == reg => insn.rm,
+			(0x89, rm, _) if rm == target => Operand::Reg(insn.reg),
+			_ => continue,
+		};
+		return Some((i, insn, source));
+	}
+	None
+}
+
+/// Returns the code from `start` up to the first branch, call or `ret` after it, which it includes.
+fn straight(code: &[u8], start: usize) -> &[u8] {
+	let mut i = start;
+	while let Some(insn) = decode(&code[i..]) {
+		i += insn.len;
+		if insn.rel.is_some() || insn.call || matches!(code[i - insn.len], 0xC2 | 0xC3) {
+			break;
+		}
+	}
+	&code[start..i]
+}
+
+/// The registers that a formatter can be found through, by their number in ModRM bytes.
+const ESP: u8 = 4;
+const EBP: u8 = 5;
+const RDI: u8 = 7;
+
+/// The operand of an instruction that its ModRM byte describes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand {
+	Reg(u8),
+	/// Memory at a displacement from a register.
+	Mem {
+		base: u8,
+		disp: isize,
+	},
+}
+
+/// What [`operands`] found out about an instruction.
+struct Operands {
+	opcode: u8,
+	/// Whether a REX prefix makes it 64-bit.
+	wide: bool,
+	/// The register in the ModRM byte.
+	reg: u8,
+	rm: Operand,
+	/// Where its immediate starts, if it has one.
+	end: usize,
+}
+
+/// Decodes the operands of an instruction that has a one-byte opcode followed by a ModRM byte, and
+/// no prefixes other than REX.
+///
+/// Memory that is absolute, relative to `rip`, or indexed by a register is not supported.
+fn operands(code: &[u8]) -> Option<Operands> {
+	let rex = match code.first() {
+		Some(&rex @ 0x40..=0x4F) if X86_64 => rex,
+		_ => 0,
+	};
+	let i = usize::from(rex != 0);
+	let [opcode, modrm, ..] = *code.get(i..)? else {
+		return None;
+	};
+	let (len, memory) = scan::modrm_len(&code[i + 1..])?;
+	let end = i + 1 + len;
+	let rm = match (memory, modrm & 7) {
+		(false, rm) => Operand::Reg(rm | (rex & 1) << 3),
+		(true, rm) => {
+			let (base, disp) = match rm {
+				4 if code[i + 2] >> 3 & 7 == 4 && rex & 2 == 0 => (code[i + 2] & 7, i + 3),
+				4 => return None,
+				_ => (rm, i + 2),
+			};
+			let disp = match (modrm >> 6, &code[disp..end]) {
+				(1, &[d]) => d as i8 as isize,
+				(2, &[a, b, c, d]) => i32::from_le_bytes([a, b, c, d]) as isize,
+				(0, &[]) => 0,
+				_ => return None,
+			};
+			Operand::Mem {
+				base: base | (rex & 1) << 3,
+				disp,
+			}
+		}
+	};
+	Some(Operands {
+		opcode,
+		wide: rex & 8 != 0,
+		reg: modrm >> 3 & 7 | (rex & 4) << 1,
+		rm,
+		end,
+	})
+}
+
+/// Returns the base register, displacement and immediate of a `test r/m8, imm8` on memory relative
+/// to a register.
+fn test_operands(code: &[u8]) -> Option<(u8, usize, u8)> {
+	let insn = operands(code)?;
+	let (0xF6, Operand::Mem { base, disp }) = (insn.opcode, insn.rm) else {
+		return None;
+	};
+	Some((base, usize::try_from(disp).ok()?, *code.get(insn.end)?))
+}
+
+/// Finds which byte of a `Formatter` the alternate flag is in, and which bit of it.
+///
+/// This formats something twice with the same formatter, with and without `#`, and compares what
+/// the formatter looked like each time.
+fn alternate_flag() -> Option<(usize, u8)> {
+	static FLAG: OnceLock<Option<(usize, u8)>> = OnceLock::new();
+
+	struct Probe<'a>(&'a RefCell<Vec<Vec<u8>>>);
+
+	impl Debug for Probe<'_> {
+		fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+			let ptr = f as *const Formatter as *const u8;
+			let bytes = unsafe { std::slice::from_raw_parts(ptr, size_of::<Formatter>()) };
+			self.0.borrow_mut().push(bytes.to_vec());
+			Ok(())
+		}
+	}
+
+	*FLAG.get_or_init(|| {
+		let seen = RefCell::new(Vec::new());
+		let mut out = String::new();
+		write!(out, "{:?}{:#?}", Probe(&seen), Probe(&seen)).ok()?;
+		let [plain, alternate] = &seen.into_inner()[..] else {
+			return None;
+		};
+		let mut diff = plain.iter().zip(alternate).enumerate();
+		let mut diff = diff.by_ref().filter(|(_, (a, b))| a != b);
+		let (i, (a, b)) = diff.next()?;
+		let bit = a ^ b;
+		(diff.next().is_none() && bit.is_power_of_two() && b & bit != 0).then_some((i, bit))
+	})
+}
+
+/// Finds where each of the builders keeps its formatter, as offsets into them.
+fn formatter_fields() -> Option<&'static [usize]> {
+	static FIELDS: OnceLock<Option<Vec<usize>>> = OnceLock::new();
+
+	struct Probe<'a>(&'a RefCell<Option<Vec<usize>>>);
+
+	impl Debug for Probe<'_> {
+		fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+			let ptr = f as *mut Formatter as usize;
+			// Each builder borrows the formatter, so they are looked at one at a time.
+			let tuple = find_word(&f.debug_tuple(""), ptr);
+			let r#struct = find_word(&f.debug_struct(""), ptr);
+			let list = find_word(&f.debug_list(), ptr);
+			let set = find_word(&f.debug_set(), ptr);
+			let map = find_word(&f.debug_map(), ptr);
+			*self.0.borrow_mut() = [tuple, r#struct, list, set, map].into_iter().collect();
+			Ok(())
+		}
+	}
+
+	FIELDS
+		.get_or_init(|| {
+			let found = RefCell::new(None);
+			write!(String::new(), "{:?}", Probe(&found)).ok()?;
+			let mut fields = found.into_inner()?;
+			fields.sort_unstable();
+			fields.dedup();
+			Some(fields)
+		})
+		.as_deref()
+}
+
+/// Returns the offset of the only aligned word in `value` that equals `word`.
+pub fn find_word<T>(value: &T, word: usize) -> Option<usize> {
+	let len = size_of::<T>() / size_of::<usize>();
+	let words = unsafe { std::slice::from_raw_parts(value as *const T as *const usize, len) };
+	let mut found = words.iter().enumerate().filter(|&(_, &w)| w == word);
+	let (i, _) = found.next()?;
+	found.next().is_none().then_some(i * size_of::<usize>())
+}
+
+/// Lists the instructions around a branch found by the scan, for error messages.
+fn listing(code: &[u8], starts: &[usize], f: &Found) -> String {
+	let first = starts.partition_point(|&i| i <= f.test).saturating_sub(4);
+	let last = starts.partition_point(|&i| i <= f.offset) + 2;
+	let mut out = String::new();
+	for (n, &i) in starts.iter().enumerate().take(last).skip(first) {
+		let insn = decode(&code[i..]).unwrap();
+		let marked = (f.test..=f.offset).contains(&i) || (i..i + insn.len).contains(&f.test);
+		let bytes = Hex(&code[i..i + insn.len]).to_string();
+		let mnemonic = mnemonic(&code[i..], i + insn.len, insn.rel);
+		let marker = if marked { '>' } else { ' ' };
+		if n != first {
+			out.push('\n');
+		}
+		let _ = write!(out, "{marker} {i:#06x}  {bytes:<30} {mnemonic}");
+	}
+	out
+}
+
+/// Names the more common instructions, along with the targets of relative branches.
+fn mnemonic(code: &[u8], end: usize, rel: Option<i32>) -> String {
+	const CC: [&str; 16] = [
+		"o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+	];
+	let mut i = 0;
+	while matches!(
+		code[i],
+		0x66 | 0x67 | 0xF0 | 0xF2 | 0xF3 | 0x2E | 0x3E | 0x64 | 0x65
+	) || X86_64 && matches!(code[i], 0x40..=0x4F)
+	{
+		i += 1;
+	}
+	let reg = code.get(i + 1).map_or(0, |m| m >> 3 & 7);
+	let name = match code[i..] {
+		[op @ 0x70..=0x7F, ..] | [0x0F, op @ 0x80..=0x8F, ..] => {
+			format!("j{}", CC[op as usize & 15])
+		}
+		[0xEB | 0xE9, ..] => "jmp".into(),
+		[0xE8, ..] => "call".into(),
+		[0xFF, ..] if reg == 2 => "call".into(),
+		[0xFF, ..] if reg == 4 => "jmp".into(),
+		[0xC3, ..] => "ret".into(),
+		[0xCC, ..] => "int3".into(),
+		[0x90, ..] | [0x0F, 0x1F, ..] => "nop".into(),
+		[0x84 | 0x85 | 0xA8 | 0xA9, ..] => "test".into(),
+		[0xF6 | 0xF7, ..] if reg == 0 => "test".into(),
+		[0x88..=0x8B | 0xB0..=0xBF | 0xC6 | 0xC7, ..] => "mov".into(),
+		[0x0F, 0xB6 | 0xB7, ..] => "movzx".into(),
+		[0x0F, 0xBE | 0xBF, ..] => "movsx".into(),
+		[0x8D, ..] => "lea".into(),
+		[0x50..=0x57, ..] => "push".into(),
+		[0x58..=0x5F, ..] => "pop".into(),
+		_ => return String::new(),
+	};
+	match rel {
+		Some(rel) => format!("{name} {:#x}", end as isize + rel as isize),
+		None => name,
+	}
+}
+
+/// Returns where a jump stub, such as a PLT entry, jumps to.
+///
+/// On x86_64 that is `jmp [rip + disp32]`, and on x86 `jmp [abs32]`, either of which may be
+/// preceded by `endbr` and have a `bnd` prefix. The 32-bit PLT of position-independent code goes
+/// through `ebx` instead, which cannot be followed without knowing what it holds.
+pub unsafe fn follow_stub(function: *const u8) -> Option<*const u8> {
+	let mut code = unsafe { std::slice::from_raw_parts(function, 16) };
+	let endbr = [0xF3, 0x0F, 0x1E, if X86_64 { 0xFA } else { 0xFB }];
+	code = code.strip_prefix(&endbr).unwrap_or(code);
+	code = code.strip_prefix(&[0xF2]).unwrap_or(code);
+	let [0xFF, 0x25, a, b, c, d, ..] = *code else {
+		return None;
+	};
+	let disp = i32::from_le_bytes([a, b, c, d]);
+	let next = code[6..].as_ptr();
+	let slot = match X86_64 {
+		true => next.wrapping_offset(disp as isize),
+		false => disp as u32 as usize as *const u8,
+	};
+	let target = unsafe { slot.cast::<*const u8>().read_unaligned() };
+	// A lazily bound entry points back into the stub until it is first called.
+	(target != next).then_some(target)
+}
+
+pub unsafe fn load(ptr: *mut Insn) -> Insn {
+	unsafe { AtomicU8::from_ptr(ptr) }.load(Ordering::SeqCst)
+}
+
+/// Stores the instruction at `ptr` by writing it to `via`, which is either the same or an alias.
+///
+/// x86 keeps its instruction caches coherent with data writes by itself, so a plain store is
+/// enough, and through an alias too.
+pub unsafe fn store(_ptr: *mut Insn, via: *mut Insn, insn: Insn) {
+	unsafe { AtomicU8::from_ptr(via) }.store(insn, Ordering::SeqCst)
+}